@@ -0,0 +1,136 @@
+//! Blocking client for the applications without the tokio runtime.
+//!
+//! Each socket has its own current-thread runtime. Connection makes progress
+//! only during the blocking calls, so pings from the server are answered on
+//! the next `send` or `recv`. Calls made from inside of the async runtime
+//! fail with `BlockingInRuntime`.
+//!
+//! # Examples
+//! ```no_run
+//! # use yarws::{Client, Error, Msg};
+//! # fn main() -> Result<(), Error> {
+//! let mut socket = yarws::blocking::connect(Client::new("ws://127.0.0.1:9001"))?;
+//! socket.send(Msg::Text("hello".to_owned()))?;
+//! if let Some(Msg::Text(text)) = socket.recv() {
+//!     println!("{}", text);
+//! }
+//! socket.close(1000, "")?;
+//! # Ok(())
+//! # }
+//! ```
+use super::{Client, Error, Msg};
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// Connects the client to the server, blocks until the handshake is done.
+pub fn connect(client: Client) -> Result<Socket, Error> {
+    let rt = runtime()?;
+    let socket = rt.block_on(client.connect())?;
+    Ok(Socket { socket, rt })
+}
+
+/// Blocking version of the [`Socket`](../struct.Socket.html).
+///
+/// Dropping the socket closes the connection without the close handshake,
+/// use `close` to close it cleanly.
+pub struct Socket {
+    // dropped before the runtime
+    socket: super::Socket,
+    rt: Runtime,
+}
+
+impl Socket {
+    /// Sends Msg to the other side of the connection. Blocks while the
+    /// outgoing buffer is full.
+    pub fn send(&mut self, msg: Msg) -> Result<(), Error> {
+        check_runtime()?;
+        self.rt.block_on(self.socket.send(msg))
+    }
+
+    /// Receives Msg from the other side of the connection, blocks until it
+    /// arrives. None is returned if the socket is closed.
+    pub fn recv(&mut self) -> Option<Msg> {
+        self.try_recv().ok()
+    }
+
+    /// Receives Msg. Errors with `SocketClosed` if the socket is closed, or
+    /// with the reason of the connection failure.
+    pub fn try_recv(&mut self) -> Result<Msg, Error> {
+        check_runtime()?;
+        self.rt.block_on(self.socket.try_recv())
+    }
+
+    /// Receives Msg waiting at most `timeout` for it. See
+    /// [`Socket::recv_timeout`](../struct.Socket.html#method.recv_timeout).
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Msg>, Error> {
+        check_runtime()?;
+        self.rt.block_on(self.socket.recv_timeout(timeout))
+    }
+
+    /// Sends close with the status and reason, and waits for the peer's
+    /// close. See [`Socket::close`](../struct.Socket.html#method.close).
+    pub fn close(self, code: u16, reason: &str) -> Result<(), Error> {
+        check_runtime()?;
+        let Socket { socket, rt } = self;
+        rt.block_on(socket.close(code, reason))
+    }
+
+    /// Underlying async socket, for the headers, subprotocol and stats of
+    /// the connection.
+    pub fn get_ref(&self) -> &super::Socket {
+        &self.socket
+    }
+}
+
+fn runtime() -> Result<Runtime, Error> {
+    check_runtime()?;
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+// Runtime can't block the thread which is already running async tasks.
+fn check_runtime() -> Result<(), Error> {
+    match Handle::try_current() {
+        Ok(_) => Err(Error::BlockingInRuntime),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn echo() {
+        let rt = runtime().unwrap();
+        let listener = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            rt.block_on(async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut socket = Server::new("").accept_stream(stream).await.unwrap();
+                while let Some(msg) = socket.recv().await {
+                    socket.send(msg).await.unwrap();
+                }
+            })
+        });
+
+        let mut socket = connect(Client::new(&format!("ws://{}", addr))).unwrap();
+        socket.send(Msg::Text("hello".to_owned())).unwrap();
+        match socket.recv() {
+            Some(Msg::Text(text)) => assert_eq!("hello", text),
+            _ => panic!("expected text"),
+        }
+        socket.close(1000, "").unwrap();
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn inside_runtime() {
+        match connect(Client::new("ws://127.0.0.1:9001")) {
+            Err(Error::BlockingInRuntime) => (),
+            _ => panic!("expected blocking in runtime"),
+        }
+    }
+}
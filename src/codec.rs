@@ -0,0 +1,1100 @@
+//! WebSocket frame codec without I/O. Encodes frames into bytes and decodes
+//! them from bytes received over any transport, so it can be used without
+//! tokio tasks: in tests, over custom transports or in WASM.
+//!
+//! Connections made by [`Client`] and [`Server`] use the same code for
+//! parsing, validation and building of the frames.
+//!
+//! # Examples
+//! ```
+//! use yarws::codec::{Codec, Opcode};
+//! let mut client = Codec::new(false);
+//! let mut server = Codec::new(true);
+//!
+//! let mut buf = client.encode(Opcode::Text, b"hello");
+//! let frame = server.decode(&mut buf).unwrap().unwrap();
+//! assert_eq!(Opcode::Text, frame.opcode());
+//! assert_eq!("hello", frame.text());
+//! assert!(buf.is_empty());
+//! ```
+//!
+//! [`Client`]: ../struct.Client.html
+//! [`Server`]: ../struct.Server.html
+use super::{DeflateStrategy, Error, Stats};
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressionStrategy, CompressorOxide, TDEFLFlush, TDEFLStatus,
+};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use rand::Rng;
+use std::cell::Cell;
+use std::fmt;
+use std::str;
+use std::sync::Arc;
+
+// Fills buffers with random bytes, with thread_rng unless the application
+// sets its own source.
+type Fill = dyn Fn(&mut [u8]) + Send + Sync;
+
+#[derive(Clone, Default)]
+pub(crate) struct Random(Option<Arc<Fill>>);
+
+impl Random {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(&mut [u8]) + Send + Sync + 'static,
+    {
+        Random(Some(Arc::new(f)))
+    }
+
+    pub(crate) fn fill(&self, buf: &mut [u8]) {
+        match &self.0 {
+            Some(f) => f(buf),
+            None => rand::thread_rng().fill(buf),
+        }
+    }
+}
+
+impl fmt::Debug for Random {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Random(custom)"),
+            None => write!(f, "Random(thread_rng)"),
+        }
+    }
+}
+
+// Negotiated permessage-deflate parameters.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deflate {
+    // Peer resets its compressor after each message, so does the Reader
+    // with decompressor. Otherwise sliding window is kept between messages.
+    pub(crate) inflate_no_context_takeover: bool,
+    // Writer resets compressor after each message.
+    pub(crate) deflate_no_context_takeover: bool,
+    // LZ77 sliding window size (8-15 bits) of the peer's compressor and of
+    // ours. Decompressor always has the largest window, so any peer's
+    // window fits.
+    pub(crate) inflate_window_bits: u8,
+    pub(crate) deflate_window_bits: u8,
+}
+
+pub(crate) const MAX_WINDOW_BITS: u8 = 15;
+pub(crate) const MIN_WINDOW_BITS: u8 = 8;
+
+// Data messages shorter than this are sent uncompressed, deflate would make
+// them only larger.
+pub(crate) const DEFLATE_THRESHOLD: usize = 64;
+pub(crate) const DEFLATE_LEVEL: u8 = 6;
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Self {
+            inflate_no_context_takeover: false,
+            deflate_no_context_takeover: false,
+            inflate_window_bits: MAX_WINDOW_BITS,
+            deflate_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+/// Frame decoded by the [`Codec`]. Fragmented message is decoded into a
+/// single frame with the opcode of the first fragment.
+///
+/// [`Codec`]: struct.Codec.html
+#[derive(Debug)]
+pub struct Frame {
+    pub(crate) fin: bool,
+    pub(crate) rsv1: bool,
+    pub(crate) rsv: u8,
+    pub(crate) mask: bool,
+    pub(crate) opcode: Opcode,
+    pub(crate) payload_len: u64,
+    pub(crate) header_len: u8,
+    pub(crate) masking_key: [u8; 4],
+    pub(crate) payload: Vec<u8>,
+    pub(crate) text_payload: String,
+}
+
+// Default limit for the size of the incoming message.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+pub(crate) const STATUS_PROTOCOL_ERROR: u16 = 1002;
+pub(crate) const STATUS_NOT_VALID_UTF8: u16 = 1007;
+pub(crate) const STATUS_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Type of the frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    // data frame types
+    Continuation,
+    Text,
+    Binary,
+    // control frame types
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    pub(crate) fn from_u8(opcode: u8) -> Self {
+        match opcode {
+            0 => Opcode::Continuation,
+            1 => Opcode::Text,
+            2 => Opcode::Binary,
+            8 => Opcode::Close,
+            9 => Opcode::Ping,
+            10 => Opcode::Pong,
+            n => Opcode::Reserved(n),
+        }
+    }
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0,
+            Opcode::Text => 1,
+            Opcode::Binary => 2,
+            Opcode::Close => 8,
+            Opcode::Ping => 9,
+            Opcode::Pong => 10,
+            Opcode::Reserved(n) => n,
+        }
+    }
+    pub(crate) fn data(self) -> bool {
+        matches!(self, Opcode::Text | Opcode::Binary)
+    }
+    pub(crate) fn control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+    pub(crate) fn desc(self) -> &'static str {
+        match self {
+            Opcode::Continuation => "continuation",
+            Opcode::Text => "text",
+            Opcode::Binary => "binary",
+            Opcode::Close => "close",
+            Opcode::Ping => "ping",
+            Opcode::Pong => "pong",
+            Opcode::Reserved(_) => "reserved",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.desc())
+    }
+}
+
+enum Fragment {
+    Start,
+    Middle,
+    End,
+    None,
+}
+
+impl Frame {
+    pub(crate) fn new(byte1: u8, byte2: u8) -> Frame {
+        Frame {
+            fin: byte1 & 0b1000_0000u8 != 0,
+            rsv1: byte1 & 0b0100_0000u8 != 0,
+            rsv: (byte1 & 0b0111_0000u8) >> 4,
+            opcode: Opcode::from_u8(byte1 & 0b0000_1111u8),
+            mask: byte2 & 0b1000_0000u8 != 0,
+            payload_len: (byte2 & 0b0111_1111u8) as u64,
+            header_len: 2,
+            masking_key: [0; 4],
+            payload: vec![0; 0],
+            text_payload: String::new(),
+        }
+    }
+
+    // length of the rest of the header after first two bytes
+    pub(crate) fn var_header_len(&mut self) -> Option<usize> {
+        if !self.mask && self.payload_len < 126 {
+            return None;
+        }
+        let mut n: usize = if self.mask { 4 } else { 0 };
+        if self.payload_len >= 126 {
+            n += 2;
+            if self.payload_len == 127 {
+                n += 6;
+            }
+        }
+        self.header_len = n as u8 + 2;
+        Some(n)
+    }
+
+    pub(crate) fn set_header(&mut self, buf: &[u8]) {
+        let mask_start = if self.mask { buf.len() - 4 } else { buf.len() };
+        if mask_start == 8 {
+            let bytes: [u8; 8] = [buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]];
+            self.payload_len = u64::from_be_bytes(bytes);
+        }
+        if mask_start == 2 {
+            let bytes: [u8; 2] = [buf[0], buf[1]];
+            self.payload_len = u16::from_be_bytes(bytes) as u64;
+        }
+        if self.mask {
+            self.masking_key.copy_from_slice(&buf[mask_start..mask_start + 4]);
+        }
+    }
+
+    pub(crate) fn set_payload(&mut self, mut payload: Vec<u8>) {
+        if self.mask {
+            mask(&mut payload, self.masking_key);
+        }
+        self.payload = payload;
+    }
+
+    fn validate_rsv(&self, deflate_supported: bool) -> Result<(), Error> {
+        // rsv2 and rsv3 must be 0, no extension defining their meaning is
+        // ever negotiated
+        if self.rsv & 0b011 != 0 {
+            return Err(Error::WrongHeader("rsv2 or rsv3 set".to_owned()));
+        }
+        if self.rsv1 {
+            // rsv1 marks compressed message, only when deflate is negotiated
+            if !deflate_supported {
+                return Err(Error::WrongHeader("rsv1 without deflate".to_owned()));
+            }
+            // and only in the first frame of the data message
+            if !self.opcode.data() {
+                return Err(Error::WrongHeader(format!("rsv1 in {} frame", self.opcode)));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate(
+        &self,
+        is_server: bool,
+        deflate_supported: bool,
+        in_continuation: bool,
+    ) -> Result<(), Error> {
+        // client must mask all frames it sends to the server, server must not
+        // mask frames sent to the client
+        if is_server && !self.mask {
+            return Err(Error::WrongHeader("unmasked client frame".to_owned()));
+        }
+        if !is_server && self.mask {
+            return Err(Error::WrongHeader("masked server frame".to_owned()));
+        }
+        if let Opcode::Reserved(n) = self.opcode {
+            return Err(Error::WrongHeader(format!("reserved opcode {}", n)));
+        }
+        // most significant bit of the 64-bit payload length must be 0
+        if self.payload_len >> 63 != 0 {
+            return Err(Error::WrongHeader("payload length msb set".to_owned()));
+        }
+        // length must use the shortest form, 16-bit only for lengths >= 126,
+        // 64-bit only for lengths > 65535
+        let ext_len = self.header_len - 2 - if self.mask { 4 } else { 0 };
+        let min_len = match ext_len {
+            2 => 126,
+            8 => 65536,
+            _ => 0,
+        };
+        if self.payload_len < min_len {
+            return Err(Error::WrongHeader(format!(
+                "non-minimal payload length {} in {}-byte form",
+                self.payload_len, ext_len
+            )));
+        }
+        match self.opcode {
+            Opcode::Close | Opcode::Ping | Opcode::Pong => {
+                // control frames must be short, payload <= 125 bytes
+                // can't be split into fragments
+                if self.payload_len > 125 {
+                    return Err(Error::WrongHeader(format!(
+                        "too long control frame {} > 125",
+                        self.payload_len
+                    )));
+                }
+                if !self.fin {
+                    return Err(Error::WrongHeader("fragmented control frame".to_owned()));
+                }
+                // close payload is empty or starts with 2 bytes status code
+                if self.opcode == Opcode::Close && self.payload_len == 1 {
+                    return Err(Error::WrongHeader("close payload of 1 byte".to_owned()));
+                }
+            }
+            // continuation (waiting for more fragments) frames must be in order
+            // start/middle.../end
+            Opcode::Continuation if !in_continuation => {
+                return Err(Error::WrongHeader("not in continuation".to_owned()));
+            }
+            Opcode::Text | Opcode::Binary if in_continuation => {
+                return Err(Error::WrongHeader("new data frame during continuation".to_owned()));
+            }
+            Opcode::Continuation | Opcode::Text | Opcode::Binary | Opcode::Reserved(_) => (),
+        }
+        self.validate_rsv(deflate_supported)
+    }
+
+    // Length of the payload delivered to the application, after the
+    // decompression. Payload_len is the length on the wire.
+    pub(crate) fn message_len(&self) -> usize {
+        match self.opcode {
+            Opcode::Text => self.text_payload.len(),
+            _ => self.payload.len(),
+        }
+    }
+
+    // Invalid utf-8 text is replaced with U+FFFD when not strict.
+    pub(crate) fn validate_payload(&mut self, strict_utf8: bool) -> Result<(), Error> {
+        match self.opcode {
+            // close reason follows 2 bytes of status code
+            Opcode::Close => {
+                if self.payload.len() > 2 {
+                    self.text_payload = str::from_utf8(&self.payload[2..])?.to_owned();
+                }
+                self.status()?;
+            }
+            Opcode::Text => {
+                // payload is moved into the string, no copy
+                let payload = std::mem::take(&mut self.payload);
+                self.text_payload = match String::from_utf8(payload) {
+                    Ok(text) => text,
+                    Err(e) if !strict_utf8 => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                    Err(e) => return Err(e.utf8_error().into()),
+                };
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn fragment(&self) -> Fragment {
+        if !self.fin && self.opcode.data() {
+            return Fragment::Start;
+        }
+        if !self.fin && self.opcode == Opcode::Continuation {
+            return Fragment::Middle;
+        }
+        if self.fin && self.opcode == Opcode::Continuation {
+            return Fragment::End;
+        }
+        Fragment::None
+    }
+    pub(crate) fn is_fragment(&self) -> bool {
+        !(self.fin && self.opcode != Opcode::Continuation)
+    }
+
+    // if frame is part of the fragmented message it is appended to the current
+    // fragment returns frame, and fragment
+    // if frame is None it is not completed
+    // continuation without the started fragment is an error
+    // payload buffer of the appended continuation is left in spare
+    pub(crate) fn into_fragment(
+        self,
+        fragment: Option<Frame>,
+        spare: &mut Vec<u8>,
+    ) -> Result<(Option<Frame>, Option<Frame>), Error> {
+        let orphan = || Error::WrongHeader("continuation frame without the first fragment".to_owned());
+        match self.fragment() {
+            Fragment::Start => Ok((None, Some(self))),
+            Fragment::Middle => {
+                let mut f = fragment.ok_or_else(orphan)?;
+                *spare = f.append(self);
+                Ok((None, Some(f)))
+            }
+            Fragment::End => {
+                let mut f = fragment.ok_or_else(orphan)?;
+                *spare = f.append(self);
+                Ok((Some(f), None))
+            }
+            Fragment::None => Ok((Some(self), fragment)),
+        }
+    }
+
+    // Returns status code from the close frame payload, 0 if there is no
+    // payload. Errors if the code is not allowed on the wire.
+    pub(crate) fn status(&self) -> Result<u16, Error> {
+        if self.payload.len() < 2 {
+            return Ok(0);
+        }
+        let bytes: [u8; 2] = [self.payload[0], self.payload[1]];
+        let status = u16::from_be_bytes(bytes);
+        if !is_valid_close_status(status) {
+            return Err(Error::InvalidCloseStatus(status));
+        }
+        Ok(status)
+    }
+
+    // Appends continuation payload. Header stays from the first frame, its
+    // rsv1 marks the whole message compressed; continuations with rsv1 are
+    // rejected in validate. Returns the payload buffer of the other.
+    fn append(&mut self, other: Frame) -> Vec<u8> {
+        self.payload_len += other.payload_len;
+        self.payload.extend_from_slice(&other.payload);
+        other.payload
+    }
+}
+
+impl Frame {
+    /// Opcode of the frame, never continuation.
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    /// Payload of the binary, ping and pong frames. Empty for the text frame,
+    /// its payload is in [`text`].
+    ///
+    /// [`text`]: struct.Frame.html#method.text
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Payload of the text frame or the reason of the close frame.
+    pub fn text(&self) -> &str {
+        &self.text_payload
+    }
+
+    /// Status of the close frame, 0 if the close has no status. Status not
+    /// allowed on the wire is returned as 1002 (protocol error), the status
+    /// to reply with. Frames returned from decode have the status validated.
+    pub fn close_status(&self) -> u16 {
+        self.status().unwrap_or(STATUS_PROTOCOL_ERROR)
+    }
+}
+
+// Close status codes allowed on the wire.
+pub(crate) fn is_valid_close_status(status: u16) -> bool {
+    match status {
+        1000 | 1001 | 1002 | 1003 | 1007 | 1008 | 1009 | 1010 | 1011 => true, // defined by the protocol
+        3000..=4999 => true, // registered or private use by the applications
+        _ => false,          // reserved or not allowed on the wire (1004-1006, 1015)
+    }
+}
+
+//Converts masked data into unmasked data, or vice versa.
+//The same algorithm applies regardless of the direction of the translation,
+//e.g., the same steps are applied to ask the data as to unmask the data.
+pub(crate) fn mask(payload: &mut [u8], key: [u8; 4]) {
+    // XOR 8 bytes at once with the key repeated twice, chunks start at
+    // multiples of 8 so the key is always aligned with the chunk start
+    let key64 = u64::from_ne_bytes([key[0], key[1], key[2], key[3], key[0], key[1], key[2], key[3]]);
+    let mut chunks = payload.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        chunk.copy_from_slice(&(u64::from_ne_bytes(word) ^ key64).to_ne_bytes());
+    }
+    // loop through the remaining octets and XOR the octet with the (i modulo 4)th
+    // octet of MASK ref: https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers
+    for (i, b) in chunks.into_remainder().iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+pub(crate) struct FrameWriter {
+    pub(crate) mask: bool,
+    pub(crate) deflater: Option<Deflater>,
+    pub(crate) max_frame_size: Option<usize>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) random: Random,
+    // Frames built since the last take_frames. Writer counts them in the
+    // stats after they are written.
+    frames: Cell<u64>,
+}
+
+impl FrameWriter {
+    pub(crate) fn new(mask: bool, deflate: Option<Deflate>) -> Self {
+        Self {
+            mask,
+            deflater: deflate.map(Deflater::new),
+            max_frame_size: None,
+            stats: Arc::default(),
+            random: Random::default(),
+            frames: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn take_frames(&self) -> u64 {
+        self.frames.replace(0)
+    }
+
+    pub(crate) fn ping(&self, payload: Vec<u8>) -> Vec<u8> {
+        self.build(Opcode::Ping, false, &payload)
+    }
+
+    pub(crate) fn pong(&self, payload: Vec<u8>) -> Vec<u8> {
+        self.build(Opcode::Pong, false, &payload)
+    }
+
+    // Close without payload, with the status only, or with the status and
+    // the reason when it is not empty.
+    pub(crate) fn close(&self, close: Option<(u16, String)>) -> Vec<u8> {
+        let (status, reason) = match close {
+            Some(close) => close,
+            None => return self.build(Opcode::Close, false, &[]),
+        };
+        let mut payload = status.to_be_bytes().to_vec();
+        // control frame payload is limited to 125 bytes
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..len]);
+        self.build(Opcode::Close, false, &payload)
+    }
+
+    pub(crate) fn binary(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        self.data(Opcode::Binary, &payload)
+    }
+
+    pub(crate) fn text(&mut self, payload: String) -> Vec<u8> {
+        self.data(Opcode::Text, payload.as_bytes())
+    }
+
+    // Data frames are compressed when permessage-deflate is negotiated.
+    // Control frames are never compressed nor fragmented.
+    pub(crate) fn data(&mut self, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let compressed = match &mut self.deflater {
+            Some(deflater) if payload.len() >= DEFLATE_THRESHOLD => deflater.deflate(payload),
+            _ => None,
+        };
+        let (rsv1, payload) = match &compressed {
+            Some(compressed) => {
+                self.stats.compressed(payload.len(), compressed.len());
+                (true, compressed.as_slice())
+            }
+            None => (false, payload),
+        };
+        match self.max_frame_size {
+            Some(size) if size > 0 && payload.len() > size => self.fragments(opcode, rsv1, payload, size),
+            _ => self.build(opcode, rsv1, payload),
+        }
+    }
+
+    // Splits payload into start frame with the message opcode, continuation
+    // frames and the final frame with fin bit set. Rsv1 is set only in the
+    // first frame.
+    fn fragments(&self, opcode: Opcode, rsv1: bool, payload: &[u8], size: usize) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(payload.len() + payload.len() / size * 14 + 14);
+        for (i, chunk) in payload.chunks(size).enumerate() {
+            let first = i == 0;
+            let fin = (i + 1) * size >= payload.len();
+            let op = if first { opcode } else { Opcode::Continuation };
+            buf.extend(self.frame(fin, op, rsv1 && first, chunk));
+        }
+        buf
+    }
+
+    // Single fragment of the streamed binary message.
+    pub(crate) fn fragment(&self, first: bool, fin: bool, payload: Vec<u8>) -> Vec<u8> {
+        let opcode = if first { Opcode::Binary } else { Opcode::Continuation };
+        self.frame(fin, opcode, false, &payload)
+    }
+
+    /*
+     0                   1                   2                   3
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    +-+-+-+-+-------+-+-------------+-------------------------------+
+    |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+    |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+    |N|V|V|V|       |S|             |   (if payload len==126/127)   |
+    | |1|2|3|       |K|             |                               |
+    +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
+    |     Extended payload length continued, if payload len == 127  |
+    + - - - - - - - - - - - - - - - +-------------------------------+
+    |                               |Masking-key, if MASK set to 1  |
+    +-------------------------------+-------------------------------+
+    | Masking-key (continued)       |          Payload Data         |
+    +-------------------------------- - - - - - - - - - - - - - - - +
+    :                     Payload Data continued ...                :
+    + - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - +
+    |                     Payload Data continued ...                |
+    +---------------------------------------------------------------+
+    */
+    fn build(&self, opcode: Opcode, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        self.frame(true, opcode, rsv1, payload)
+    }
+
+    // Payload is copied into the frame and masked there, so it can be
+    // borrowed from the message shared between sockets.
+    pub(crate) fn frame(&self, fin: bool, opcode: Opcode, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(payload.len() + 14); // header is up to 14 bytes
+        buf.push(opcode.to_u8());
+        if fin {
+            buf[0] |= 0b1000_0000u8;
+        }
+        if rsv1 {
+            buf[0] |= 0b0100_0000u8;
+        }
+
+        // add payload length
+        let l = payload.len();
+        if l < 126 {
+            buf.push(l as u8);
+        } else if payload.len() < 65536 {
+            buf.push(126u8);
+            let l = l as u16;
+            buf.extend_from_slice(&l.to_be_bytes());
+        } else {
+            buf.push(127u8);
+            let l = l as u64;
+            buf.extend_from_slice(&l.to_be_bytes());
+        }
+        if self.mask {
+            buf[1] |= 0b1000_0000u8; // set masking bit
+            let mut masking_key = [0u8; 4];
+            self.random.fill(&mut masking_key); // create key
+            buf.extend_from_slice(&masking_key); // write key to msg
+            let start = buf.len();
+            buf.extend_from_slice(payload);
+            mask(&mut buf[start..], masking_key); // mask payload
+        } else {
+            buf.extend_from_slice(payload);
+        }
+        self.frames.set(self.frames.get() + 1);
+        buf
+    }
+}
+
+// Compresses outgoing messages for the permessage-deflate extension.
+// With context takeover compressor is kept between messages, otherwise new
+// one is used for each message.
+pub(crate) struct Deflater {
+    flags: u32,
+    compressor: Option<CompressorOxide>,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub(crate) fn new(deflate: Deflate) -> Self {
+        Self::with(deflate, DEFLATE_LEVEL, DeflateStrategy::Default)
+    }
+
+    pub(crate) fn with(deflate: Deflate, level: u8, strategy: DeflateStrategy) -> Self {
+        // miniz can't limit the window size, it always uses 15 bits. Window
+        // smaller than that is respected by finding only matches at distance
+        // 1 (run-length encoding), they fit into any window. Huffman only
+        // has no matches at all.
+        let strategy = match strategy {
+            DeflateStrategy::HuffmanOnly => CompressionStrategy::HuffmanOnly,
+            _ if deflate.deflate_window_bits < MAX_WINDOW_BITS => CompressionStrategy::RLE,
+            DeflateStrategy::Default => CompressionStrategy::Default,
+            DeflateStrategy::Filtered => CompressionStrategy::Filtered,
+            DeflateStrategy::Rle => CompressionStrategy::RLE,
+        };
+        Self {
+            flags: create_comp_flags_from_zip_params(level as i32, 0, strategy as i32), // raw deflate
+            compressor: None,
+            no_context_takeover: deflate.deflate_no_context_takeover,
+        }
+    }
+
+    // Compressor is sync flushed so the output ends with an empty stored
+    // block 0x00 0x00 0xff 0xff. Those 4 bytes are removed as required by
+    // RFC 7692. None if the compressor fails, the message is then sent
+    // uncompressed and the next one starts with a new compressor.
+    pub(crate) fn deflate(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        let flags = self.flags;
+        let compressor = self.compressor.get_or_insert_with(|| CompressorOxide::new(flags));
+        let mut out = vec![0u8; payload.len() / 2 + 64];
+        let mut in_pos = 0;
+        let mut out_pos = 0;
+        loop {
+            let (status, bytes_in, bytes_out) =
+                compress(compressor, &payload[in_pos..], &mut out[out_pos..], TDEFLFlush::Sync);
+            in_pos += bytes_in;
+            out_pos += bytes_out;
+            match status {
+                TDEFLStatus::Okay if in_pos == payload.len() && out_pos < out.len() => break,
+                TDEFLStatus::Okay => out.resize(out.len() * 2, 0), // need more space
+                _ => {
+                    self.compressor = None;
+                    return None;
+                }
+            }
+        }
+        if self.no_context_takeover {
+            self.compressor = None;
+        }
+        out.truncate(out_pos);
+        if out.ends_with(&[0x00, 0x00, 0xff, 0xff]) {
+            out.truncate(out_pos - 4);
+        }
+        Some(out)
+    }
+}
+
+// Decompresses incoming messages for the permessage-deflate extension.
+// Decompressor state is kept between messages, so the peer can reference
+// data from the previous messages (context takeover).
+pub(crate) struct Inflater {
+    state: Box<InflateState>,
+    no_context_takeover: bool,
+    reset: bool,
+}
+
+impl Inflater {
+    pub(crate) fn new(deflate: Deflate) -> Self {
+        Self {
+            state: InflateState::new_boxed(DataFormat::Raw),
+            no_context_takeover: deflate.inflate_no_context_takeover,
+            reset: false,
+        }
+    }
+
+    // Output is limited to max_size bytes. Decompression stops as soon as
+    // that is exceeded, output is never allocated beyond the limit.
+    pub(crate) fn inflate(&mut self, payload: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+        // empty message can be sent without any block, trailer alone is not
+        // a valid block and would break the context for the next message
+        if payload.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.reset || self.no_context_takeover {
+            self.state.reset(DataFormat::Raw);
+            self.reset = false;
+        }
+        // append 4 bytes removed by the compressor, sender strips them from
+        // the end of each message
+        let mut input = Vec::with_capacity(payload.len() + 4);
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+
+        let limit = max_size.saturating_add(1);
+        let mut out = vec![0u8; (payload.len() * 2 + 64).min(limit)];
+        let mut in_pos = 0;
+        let mut out_pos = 0;
+        loop {
+            let res = inflate(&mut self.state, &input[in_pos..], &mut out[out_pos..], MZFlush::None);
+            in_pos += res.bytes_consumed;
+            out_pos += res.bytes_written;
+            match res.status {
+                Ok(MZStatus::StreamEnd) => {
+                    // final block, peer starts new stream with the next message
+                    self.reset = true;
+                    break;
+                }
+                Ok(_) | Err(MZError::Buf) if out_pos == out.len() => {
+                    if out.len() >= limit {
+                        return Err(Error::MessageTooBig(max_size));
+                    }
+                    out.resize((out.len() * 2).min(limit), 0); // need more space
+                }
+                Ok(_) | Err(MZError::Buf) if res.bytes_consumed > 0 || res.bytes_written > 0 => (),
+                // all input consumed and no more output
+                Ok(_) | Err(MZError::Buf) if in_pos == input.len() => break,
+                _ => return Err(Error::InflateFailed(format!("{:?}", res.status))),
+            }
+        }
+        out.truncate(out_pos);
+        Ok(out)
+    }
+}
+
+// Incoming message assembled from frames. Reader reads frames from the
+// stream and Codec from the buffer, both validate the header before the
+// payload is read and pass frames with payload to the message.
+pub(crate) struct Decoder {
+    is_server: bool,
+    inflater: Option<Inflater>,
+    // first frame of the fragmented message with appended continuations
+    fragment: Option<Frame>,
+    // payload buffer of the last appended continuation, reused for the next
+    spare: Vec<u8>,
+    fragments: usize,
+    pub(crate) max_message_size: usize,
+    pub(crate) max_fragments: Option<usize>,
+    pub(crate) strict_utf8: bool,
+    pub(crate) stats: Arc<Stats>,
+}
+
+impl Decoder {
+    pub(crate) fn new(is_server: bool, deflate: Option<Deflate>) -> Self {
+        Self {
+            is_server,
+            inflater: deflate.map(Inflater::new),
+            fragment: None,
+            spare: Vec::new(),
+            fragments: 0,
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_fragments: None,
+            strict_utf8: true,
+            stats: Arc::default(),
+        }
+    }
+
+    // Validates frame header. Size of the message is checked before
+    // allocating payload buffer, number of fragments before appending, so
+    // the cheap empty continuations count too.
+    pub(crate) fn header(&self, frame: &Frame) -> Result<(), Error> {
+        frame.validate(self.is_server, self.inflater.is_some(), self.fragment.is_some())?;
+        let message_len = match &self.fragment {
+            Some(f) if !frame.opcode.control() => f.payload_len.saturating_add(frame.payload_len),
+            _ => frame.payload_len,
+        };
+        if message_len > self.max_message_size as u64 {
+            return Err(Error::MessageTooBig(self.max_message_size));
+        }
+        if let Some(max) = self.max_fragments {
+            if frame.is_fragment() && self.next_fragment() > max {
+                return Err(Error::TooManyFragments(max));
+            }
+        }
+        Ok(())
+    }
+
+    fn next_fragment(&self) -> usize {
+        match self.fragment {
+            Some(_) => self.fragments + 1,
+            None => 1,
+        }
+    }
+
+    // Returns complete message, None if the frame is fragment and more are
+    // expected. Control frames can arrive between fragments, they are
+    // returned right away keeping the pending fragment.
+    pub(crate) fn message(&mut self, frame: Frame) -> Result<Option<Frame>, Error> {
+        let mut frame = if frame.is_fragment() {
+            self.fragments = self.next_fragment();
+            let (frame, fragment) = frame.into_fragment(self.fragment.take(), &mut self.spare)?;
+            self.fragment = fragment;
+            match frame {
+                Some(f) => f,
+                None => return Ok(None),
+            }
+        } else {
+            frame
+        };
+        self.inflate(&mut frame)?;
+        frame.validate_payload(self.strict_utf8)?;
+        Ok(Some(frame))
+    }
+
+    // Buffer for reading the frame payload. Continuation payload is only
+    // copied to the fragmented message, so its buffer is reused between
+    // frames. Other frames keep the buffer as the message payload.
+    pub(crate) fn payload_buf(&mut self, frame: &Frame) -> Vec<u8> {
+        let len = frame.payload_len as usize;
+        let mut buf = match frame.opcode {
+            Opcode::Continuation => std::mem::take(&mut self.spare),
+            _ => Vec::new(),
+        };
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    // Drops the pending fragment, close can interrupt the fragmented
+    // message which is never completed then.
+    pub(crate) fn take_fragment(&mut self) -> Option<Frame> {
+        self.fragment.take()
+    }
+
+    // Decompresses payload of the complete message, fragmented message is
+    // inflated once after all fragments are appended. Compressed fragments
+    // are already limited by the header check, output of the inflater is
+    // limited to the same size.
+    fn inflate(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if let Some(inflater) = &mut self.inflater {
+            if frame.rsv1 {
+                let compressed_len = frame.payload.len();
+                frame.payload = inflater.inflate(&frame.payload, self.max_message_size)?;
+                self.stats.inflated(frame.payload.len(), compressed_len);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Status of the close frame to send to the peer when decoding fails with
+/// the error.
+pub fn close_status(error: &Error) -> u16 {
+    match error {
+        Error::TextPayloadNotValidUTF8(_) => STATUS_NOT_VALID_UTF8,
+        Error::MessageTooBig(_) | Error::TooManyFragments(_) => STATUS_MESSAGE_TOO_BIG,
+        _ => STATUS_PROTOCOL_ERROR,
+    }
+}
+
+/// Encoder and decoder of the frames for one side of the connection.
+/// Validates incoming frames and assembles fragmented messages as the
+/// connections made by Client and Server. Compression is never used, frames
+/// with rsv1 bit set are rejected.
+pub struct Codec {
+    decoder: Decoder,
+    writer: FrameWriter,
+}
+
+impl Codec {
+    /// Creates codec for the server side of the connection, or the client
+    /// side if `is_server` is false. Server expects masked frames and sends
+    /// unmasked, client the opposite.
+    pub fn new(is_server: bool) -> Self {
+        Self {
+            decoder: Decoder::new(is_server, None),
+            writer: FrameWriter::new(!is_server, None),
+        }
+    }
+
+    /// Sets maximum size of the decoded message. Default is 64 MiB.
+    pub fn max_message_size(mut self, size: usize) -> Codec {
+        self.decoder.max_message_size = size;
+        self
+    }
+
+    /// Sets maximum number of frames of the fragmented message. Unlimited by
+    /// default.
+    pub fn max_fragments_per_message(mut self, max: usize) -> Codec {
+        self.decoder.max_fragments = Some(max);
+        self
+    }
+
+    /// Text with invalid utf-8 fails decoding with status 1007 (default). When
+    /// not strict text is decoded lossily, invalid sequences are replaced with
+    /// U+FFFD. That is not conformant with the RFC 6455.
+    pub fn strict_utf8(mut self, strict: bool) -> Codec {
+        self.decoder.strict_utf8 = strict;
+        self
+    }
+
+    /// Encodes payload into a single frame. Payload of the close frame is 2
+    /// bytes status followed by the reason, control frame payload must not
+    /// be longer than 125 bytes.
+    pub fn encode(&mut self, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        self.writer.frame(true, opcode, false, payload)
+    }
+
+    /// Decodes message from the start of the buffer. Decoded frames are
+    /// removed from the buffer. Returns None when the buffer doesn't hold the
+    /// whole message, call again when more bytes are appended. After error
+    /// the connection should be closed with the [`close_status`].
+    ///
+    /// [`close_status`]: fn.close_status.html
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Frame>, Error> {
+        loop {
+            if buf.len() < 2 {
+                return Ok(None);
+            }
+            let mut frame = Frame::new(buf[0], buf[1]);
+            let header_len = 2 + frame.var_header_len().unwrap_or(0);
+            if buf.len() < header_len {
+                return Ok(None);
+            }
+            frame.set_header(&buf[2..header_len]);
+            self.decoder.header(&frame)?;
+            let frame_len = header_len + frame.payload_len as usize;
+            if buf.len() < frame_len {
+                return Ok(None);
+            }
+            frame.set_payload(buf[header_len..frame_len].to_vec());
+            buf.drain(..frame_len);
+            if let Some(frame) = self.decoder.message(frame)? {
+                return Ok(Some(frame));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_partial_buffer() {
+        let mut client = Codec::new(false);
+        let mut server = Codec::new(true);
+        let raw = client.encode(Opcode::Binary, &[7u8; 300]);
+        // frame is decoded only when all of its bytes are in the buffer
+        let mut buf = Vec::new();
+        for b in &raw[..raw.len() - 1] {
+            buf.push(*b);
+            assert!(server.decode(&mut buf).unwrap().is_none());
+        }
+        buf.push(raw[raw.len() - 1]);
+        buf.extend(client.encode(Opcode::Ping, b"p"));
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Opcode::Binary, frame.opcode());
+        assert_eq!(&[7u8; 300][..], frame.payload());
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Opcode::Ping, frame.opcode());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn codec_fragments() {
+        let mut server = Codec::new(true);
+        let mut client = Codec::new(false);
+        let mut buf = client.writer.frame(false, Opcode::Text, false, b"hello ");
+        buf.extend(client.encode(Opcode::Pong, b""));
+        buf.extend(client.writer.frame(true, Opcode::Continuation, false, b"world"));
+        // control frame between fragments comes first
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Opcode::Pong, frame.opcode());
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Opcode::Text, frame.opcode());
+        assert_eq!("hello world", frame.text());
+
+        let mut server = Codec::new(true).max_fragments_per_message(2);
+        let mut buf = client.writer.frame(false, Opcode::Binary, false, b"");
+        buf.extend(client.writer.frame(false, Opcode::Continuation, false, b""));
+        buf.extend(client.writer.frame(true, Opcode::Continuation, false, b""));
+        let err = server.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&err));
+    }
+
+    #[test]
+    fn codec_close() {
+        let mut server = Codec::new(false);
+        let mut client = Codec::new(true);
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"going away");
+        let mut buf = server.encode(Opcode::Close, &payload);
+        let frame = client.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Opcode::Close, frame.opcode());
+        assert_eq!(1001, frame.close_status());
+        assert_eq!("going away", frame.text());
+
+        let mut buf = server.encode(Opcode::Close, &[]);
+        assert_eq!(0, client.decode(&mut buf).unwrap().unwrap().close_status());
+
+        let mut buf = server.encode(Opcode::Close, &1005u16.to_be_bytes());
+        let err = client.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&err));
+
+        let mut buf = server.encode(Opcode::Close, &[0x03]);
+        let err = client.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&err));
+    }
+
+    #[test]
+    fn codec_errors() {
+        // unmasked frame sent to the server
+        let mut server = Codec::new(true);
+        let mut buf = Codec::new(true).encode(Opcode::Text, b"abc");
+        let err = server.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&err));
+
+        // size is checked from the header
+        let mut server = Codec::new(true).max_message_size(10);
+        let mut buf = Codec::new(false).encode(Opcode::Binary, &[0u8; 11]);
+        buf.truncate(6);
+        let err = server.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&err));
+
+        let mut server = Codec::new(true);
+        let mut buf = Codec::new(false).encode(Opcode::Text, &[0xff, 0xfe]);
+        let err = server.decode(&mut buf).unwrap_err();
+        assert_eq!(STATUS_NOT_VALID_UTF8, close_status(&err));
+    }
+
+    #[test]
+    fn deflate_failed() {
+        let mut deflater = Deflater::new(Deflate::default());
+        // finished compressor rejects more input
+        let mut compressor = CompressorOxide::new(deflater.flags);
+        compress(&mut compressor, &[], &mut [0u8; 16], TDEFLFlush::Finish);
+        deflater.compressor = Some(compressor);
+        assert!(deflater.deflate(b"hello hello").is_none());
+
+        // next message gets new compressor
+        let msg = deflater.deflate(b"hello hello").unwrap();
+        let mut inflater = Inflater::new(Deflate::default());
+        assert_eq!(b"hello hello", &inflater.inflate(&msg, 100).unwrap()[..]);
+    }
+}
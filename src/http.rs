@@ -1,105 +1,397 @@
+use super::codec::{Deflate, Random, MAX_WINDOW_BITS, MIN_WINDOW_BITS};
 use super::stream::Stream;
 use super::{Error, Url};
 use base64;
-use rand::Rng;
+use futures::future::BoxFuture;
 use sha1::{Digest, Sha1};
 use std::collections::HashMap;
 use std::str;
 use tokio;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+// Default limits for the size of the http header.
+pub const MAX_HEADER_LINES: usize = 100;
+pub const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+// Application check of the upgrade request headers.
+pub type RequestCheck = dyn Fn(&HashMap<String, String>) -> bool + Send + Sync;
+
+// Asynchronous application authorization of the upgrade request. Rejects
+// with the response status and headers.
+pub type Authorize =
+    dyn Fn(HashMap<String, String>) -> BoxFuture<'static, Result<(), (u16, HashMap<String, String>)>> + Send + Sync;
+
+// Server side options of the opening handshake.
+pub struct AcceptConfig {
+    // Subprotocols supported by the server, the first of the client's
+    // requested which is in the list is selected.
+    pub protocols: Vec<String>,
+    // Extra headers of the upgrade response.
+    pub headers: HashMap<String, String>,
+    // Requests with larger header are rejected with 431.
+    pub max_header_lines: usize,
+    pub max_header_size: usize,
+    // Deflate window of both sides is limited to that.
+    pub max_window_bits: u8,
+    // Requests with Host header not in the non empty list are rejected with
+    // 421.
+    pub allowed_hosts: Vec<String>,
+    // Requests for which check returns false are rejected with 403.
+    pub check: Option<Box<RequestCheck>>,
+    // Requests rejected by authorize get the status and headers it returns.
+    pub authorize: Option<Box<Authorize>>,
+}
+
+impl Default for AcceptConfig {
+    fn default() -> Self {
+        AcceptConfig {
+            protocols: Vec::new(),
+            headers: [("Server".to_owned(), "yarws".to_owned())].iter().cloned().collect(),
+            max_header_lines: MAX_HEADER_LINES,
+            max_header_size: MAX_HEADER_SIZE,
+            max_window_bits: MAX_WINDOW_BITS,
+            allowed_hosts: Vec::new(),
+            check: None,
+            authorize: None,
+        }
+    }
+}
+
+// Client side options of the opening handshake.
+#[derive(Debug, Clone)]
+pub struct ConnectConfig {
+    // Extra headers of the upgrade request.
+    pub headers: HashMap<String, String>,
+    // Requested subprotocols, in the order of preference.
+    pub protocols: Vec<String>,
+    // Offer permessage-deflate.
+    pub compression: bool,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        ConnectConfig {
+            headers: [("User-Agent".to_owned(), "yarws".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            protocols: Vec::new(),
+            compression: true,
+        }
+    }
+}
+
+// Result of the successful handshake.
+pub struct Handshake<R, W> {
+    pub stream: Stream<R, W>,
+    // negotiated permessage-deflate, None if not supported by the peer
+    pub deflate: Option<Deflate>,
+    pub headers: HashMap<String, String>,
+    // selected subprotocol
+    pub protocol: Option<String>,
+    // request path and query string
+    pub path: String,
+    pub query: Option<String>,
+}
+
 // Accepts http upgrade requests.
 // Parses http headers. Checks weather it is valid WebSocket upgrade request.
 // Responds to client with http upgrade response.
-pub async fn accept<R, W>(mut stream: Stream<R, W>) -> Result<(Stream<R, W>, bool, HashMap<String, String>), Error>
+// Negotiates subprotocol and extensions, and checks the request, as set in
+// the config.
+pub async fn accept<R, W>(mut stream: Stream<R, W>, config: &AcceptConfig) -> Result<Handshake<R, W>, Error>
 where
     R: AsyncRead + std::marker::Unpin,
     W: AsyncWrite + std::marker::Unpin,
 {
-    let lines = stream.rh.http_header().await?;
-    let header = Header::from_lines(&lines);
-    if header.is_valid_upgrade() {
-        stream.wh.write(header.upgrade_response().as_bytes()).await?;
-        return Ok((stream, header.is_deflate_supported(), header.lines));
+    let lines = match stream
+        .rh
+        .http_header(config.max_header_lines, config.max_header_size)
+        .await
+    {
+        Err(Error::HeaderTooLarge) => {
+            const TOO_LARGE_HTTP_RESPONSE: &[u8] = "HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n".as_bytes();
+            stream.wh.write(TOO_LARGE_HTTP_RESPONSE).await?;
+            return Err(Error::HeaderTooLarge);
+        }
+        other => other?,
+    };
+    let mut header = Header::from_lines(&lines);
+    let request_line_ok = header.parse_request_line().is_ok();
+    if request_line_ok && header.is_websocket_request() && header.method != "GET" {
+        // upgrade is allowed only for GET requests
+        const METHOD_NOT_ALLOWED_HTTP_RESPONSE: &[u8] =
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n\r\n".as_bytes();
+        stream.wh.write(METHOD_NOT_ALLOWED_HTTP_RESPONSE).await?;
+        return Err(Error::MethodNotAllowed(header.method));
+    }
+    if request_line_ok && header.is_valid_upgrade() {
+        if !config.allowed_hosts.is_empty() && !is_allowed_host(&header.host, &config.allowed_hosts) {
+            const MISDIRECTED_HTTP_RESPONSE: &[u8] = "HTTP/1.1 421 Misdirected Request\r\n\r\n".as_bytes();
+            stream.wh.write(MISDIRECTED_HTTP_RESPONSE).await?;
+            return Err(Error::HostNotAllowed(header.host));
+        }
+        if let Some(check) = &config.check {
+            if !check(&header.lines) {
+                const FORBIDDEN_HTTP_RESPONSE: &[u8] = "HTTP/1.1 403 Forbidden\r\n\r\n".as_bytes();
+                stream.wh.write(FORBIDDEN_HTTP_RESPONSE).await?;
+                return Err(Error::RequestRejected);
+            }
+        }
+        if let Some(authorize) = &config.authorize {
+            if let Err((status, headers)) = authorize(header.lines.clone()).await {
+                stream.wh.write(rejected_response(status, &headers).as_bytes()).await?;
+                return Err(Error::RequestRejected);
+            }
+        }
+        let protocol = header.select_protocol(&config.protocols);
+        let deflate = header.accept_deflate(config.max_window_bits);
+        let response = header.upgrade_response(protocol.as_deref(), deflate.as_ref(), &config.headers);
+        stream.wh.write(response.as_bytes()).await?;
+        return Ok(Handshake {
+            stream,
+            deflate,
+            headers: header.lines,
+            protocol,
+            path: header.path,
+            query: header.query,
+        });
+    }
+    if request_line_ok && header.is_websocket_request() && header.version != "13" {
+        // tell the client which version we support
+        const UPGRADE_REQUIRED_HTTP_RESPONSE: &[u8] =
+            "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\n\r\n".as_bytes();
+        stream.wh.write(UPGRADE_REQUIRED_HTTP_RESPONSE).await?;
+        return Err(Error::UnsupportedVersion(header.version));
     }
     const BAD_REQUEST_HTTP_RESPONSE: &[u8] = "HTTP/1.1 400 Bad Request\r\n\r\n".as_bytes();
     stream.wh.write(BAD_REQUEST_HTTP_RESPONSE).await?;
     Err(Error::InvalidUpgradeRequest)
 }
 
+// Response to the request rejected by the application. Status which is not
+// an error is sent as 500 and invalid headers are left out, so the
+// application can't split the response. Headers are sorted for deterministic
+// output.
+fn rejected_response(status: u16, headers: &HashMap<String, String>) -> String {
+    let status = match status {
+        400..=599 => status,
+        _ => 500,
+    };
+    let reason = match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let mut s = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    let mut keys: Vec<&String> = headers
+        .iter()
+        .filter(|(k, v)| is_valid_header(k, v))
+        .map(|(k, _)| k)
+        .collect();
+    keys.sort();
+    for key in keys {
+        s.push_str(&format!("{}: {}\r\n", key, headers[key]));
+    }
+    s.push_str("\r\n");
+    s
+}
+
 // Connects to the WebSocket server.
 // It will send http upgrade request, wait for response and check whether
-// upgrade request is accepted.
+// upgrade request is accepted. Without compression permessage-deflate is not
+// offered and any extension in the response is ignored.
 pub async fn connect<R, W>(
     mut stream: Stream<R, W>,
     url: &Url,
-    headers: Option<HashMap<String, String>>,
-) -> Result<(Stream<R, W>, bool, HashMap<String, String>), Error>
+    config: &ConnectConfig,
+    random: &Random,
+) -> Result<Handshake<R, W>, Error>
 where
     R: AsyncRead + std::marker::Unpin,
     W: AsyncWrite + std::marker::Unpin,
 {
-    let key = connect_key();
-    stream
-        .wh
-        .write(connect_header(&url.addr, &url.path, &key, headers).as_bytes())
-        .await?;
+    let key = connect_key(random);
+    let request = connect_header(
+        &url.addr,
+        &url.path,
+        &key,
+        &config.protocols,
+        Some(config.headers.clone()),
+        config.compression,
+    );
+    stream.wh.write(request.as_bytes()).await?;
 
-    let lines = stream.rh.http_header().await?;
+    let lines = stream.rh.http_header(MAX_HEADER_LINES, MAX_HEADER_SIZE).await?;
     let header = Header::from_lines(&lines);
-    if header.is_valid_connect(&key) {
-        return Ok((stream, header.is_deflate_supported(), header.lines));
+    let status = header.status();
+    if status != Some(101) {
+        return Err(Error::HandshakeFailed {
+            status: status.unwrap_or(0),
+            headers: header.lines,
+        });
+    }
+    header.validate_connect(&key, &config.protocols)?;
+    let deflate = if config.compression {
+        header.connect_deflate()?
+    } else {
+        None
+    };
+    let (path, query) = split_target(&url.path);
+    Ok(Handshake {
+        stream,
+        deflate,
+        protocol: header.protocols.first().cloned(),
+        headers: header.lines,
+        path,
+        query,
+    })
+}
+
+// Establishes tunnel through the http proxy to the addr (host:port).
+// Sends CONNECT request, with Proxy-Authorization header if auth is set, and
+// waits for the 200 response. After that stream is connected to the addr.
+pub async fn proxy_connect<R, W>(stream: &mut Stream<R, W>, addr: &str, auth: Option<&str>) -> Result<(), Error>
+where
+    R: AsyncRead + std::marker::Unpin,
+    W: AsyncWrite + std::marker::Unpin,
+{
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", addr, addr);
+    if let Some(auth) = auth {
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+    }
+    req.push_str("\r\n");
+    stream.wh.write(req.as_bytes()).await?;
+
+    let lines = stream.rh.http_header(MAX_HEADER_LINES, MAX_HEADER_SIZE).await?;
+    let status_line = lines.first().map(|l| l.as_str()).unwrap_or("");
+    let mut parts = status_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some("200")) if version.starts_with("HTTP/") => Ok(()),
+        _ => Err(Error::ProxyConnectFailed(status_line.to_owned())),
     }
-    Err(Error::InvalidUpgradeRequest)
 }
 
 #[derive(Debug)]
 struct Header {
+    // request or status line
+    start_line: String,
+    method: String,
+    path: String,
+    query: Option<String>,
     connection: String,
     upgrade: String,
     version: String,
     key: String,
+    host: String,
+    // number of Host lines, request with more than one is invalid
+    hosts: usize,
     extensions: String,
     accept: String,
+    protocols: Vec<String>,
     lines: HashMap<String, String>,
 }
 
 impl Header {
     fn new() -> Header {
         Header {
+            start_line: String::new(),
+            method: String::new(),
+            path: String::new(),
+            query: None,
             connection: String::new(),
             upgrade: String::new(),
             version: String::new(),
             key: String::new(),
+            host: String::new(),
+            hosts: 0,
             extensions: String::new(),
             accept: String::new(),
+            protocols: Vec::new(),
             lines: HashMap::new(),
         }
     }
 
-    fn from_lines(lines: &Vec<String>) -> Self {
+    // First line is request or status line, others are headers.
+    fn from_lines(lines: &[String]) -> Self {
         let mut header = Header::new();
-        for line in lines {
-            header.append(&line);
+        if let Some((first, rest)) = lines.split_first() {
+            header.start_line = first.to_owned();
+            for line in rest {
+                header.append(line);
+            }
         }
         header
     }
 
+    // Status code from the response status line: HTTP/1.1 101 Switching Protocols
+    fn status(&self) -> Option<u16> {
+        let mut parts = self.start_line.split_whitespace();
+        match parts.next() {
+            Some(version) if version.starts_with("HTTP/") => parts.next()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Parses request line in form: GET /chat?room=5 HTTP/1.1
+    // Target can be also in absolute form: GET ws://host/chat?room=5 HTTP/1.1
+    fn parse_request_line(&mut self) -> Result<(), Error> {
+        let mut parts = self.start_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) => (m, t, v),
+            _ => return Err(Error::InvalidUpgradeRequest),
+        };
+        let target = match origin_form(target) {
+            Some(t) if !method.is_empty() && version.starts_with("HTTP/") => t,
+            _ => return Err(Error::InvalidUpgradeRequest),
+        };
+        let (path, query) = split_target(&target);
+        self.method = method.to_owned();
+        self.path = path;
+        self.query = query;
+        Ok(())
+    }
+
     fn append(&mut self, line: &str) {
         if let Some((key, value)) = split_header_line(&line) {
-            self.lines.insert(key.to_owned(), value.to_owned());
+            self.add_line(key, value);
             match key.to_lowercase().as_str() {
-                "connection" => self.connection = value.to_lowercase(),
-                "upgrade" => self.upgrade = value.to_lowercase(),
+                "connection" => add_tokens(&mut self.connection, value),
+                "upgrade" => add_tokens(&mut self.upgrade, value),
                 "sec-websocket-version" => self.version = value.to_string(),
                 "sec-websocket-key" => self.key = value.to_string(),
+                "host" => {
+                    self.host = value.to_string();
+                    self.hosts += 1;
+                }
                 "sec-websocket-extensions" => self.add_extensions(value),
                 "sec-websocket-accept" => self.accept = value.to_string(),
+                "sec-websocket-protocol" => self.add_protocols(value),
                 _ => (),
             }
         }
     }
 
+    // Values of the repeated header are joined into one list, as if sent in
+    // one line (RFC 7230 3.2.2). Cookie pairs are separated with semicolon.
+    // Key is kept as in the first line.
+    fn add_line(&mut self, key: &str, value: &str) {
+        match self.lines.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, values)) => {
+                values.push_str(if key.eq_ignore_ascii_case("cookie") { "; " } else { ", " });
+                values.push_str(value);
+            }
+            None => {
+                self.lines.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+
     fn add_extensions(&mut self, ex: &str) {
         if !self.extensions.is_empty() {
             self.extensions.push_str(", ");
@@ -107,39 +399,302 @@ impl Header {
         self.extensions.push_str(ex);
     }
 
-    fn is_deflate_supported(&self) -> bool {
-        self.extensions.contains("permessage-deflate")
+    // Protocols are comma separated list, header can be repeated.
+    fn add_protocols(&mut self, value: &str) {
+        for p in value.split(',') {
+            let p = p.trim();
+            if !p.is_empty() {
+                self.protocols.push(p.to_owned());
+            }
+        }
+    }
+
+    // First of the requested protocols supported by the server.
+    fn select_protocol(&self, supported: &[String]) -> Option<String> {
+        self.protocols.iter().find(|p| supported.contains(p)).cloned()
     }
 
-    fn upgrade_response(&self) -> String {
+    // Server side of the deflate negotiation. Client can offer
+    // permessage-deflate more than once with different parameters, first
+    // acceptable offer is selected. None if there is no such offer.
+    fn accept_deflate(&self, max_window_bits: u8) -> Option<Deflate> {
+        parse_extensions(&self.extensions)
+            .iter()
+            .filter(|ext| ext.name == "permessage-deflate")
+            .find_map(|ext| accept_deflate_offer(&ext.params, max_window_bits))
+    }
+
+    // Client side of the deflate negotiation, from the server response.
+    // Errors if the server responds with extension or parameters which are
+    // not offered or not valid.
+    fn connect_deflate(&self) -> Result<Option<Deflate>, Error> {
+        let exts = parse_extensions(&self.extensions);
+        match exts.as_slice() {
+            [] => Ok(None),
+            [ext] if ext.name == "permessage-deflate" => match connect_deflate_response(&ext.params) {
+                Some(deflate) => Ok(Some(deflate)),
+                None => Err(Error::WrongHeader(format!(
+                    "invalid extension response: {}",
+                    self.extensions
+                ))),
+            },
+            _ => Err(Error::WrongHeader(format!(
+                "extension not offered: {}",
+                self.extensions
+            ))),
+        }
+    }
+
+    fn upgrade_response(
+        &self,
+        protocol: Option<&str>,
+        deflate: Option<&Deflate>,
+        headers: &HashMap<String, String>,
+    ) -> String {
         const HEADER: &str = "HTTP/1.1 101 Switching Protocols\r\n\
             Upgrade: websocket\r\n\
-            Server: yarws\r\n\
             Connection: Upgrade\r\n\
             Sec-WebSocket-Accept: ";
         let mut s = HEADER.to_string();
         s.push_str(&ws_accept(&self.key));
         s.push_str(&"\r\n");
-        if self.is_deflate_supported() {
-            s.push_str(
-                "Sec-WebSocket-Extensions: permessage-deflate;client_no_context_takeover;server_no_context_takeover",
-            );
+        if let Some(deflate) = deflate {
+            s.push_str("Sec-WebSocket-Extensions: permessage-deflate");
+            if deflate.deflate_no_context_takeover {
+                s.push_str(";server_no_context_takeover");
+            }
+            if deflate.inflate_no_context_takeover {
+                s.push_str(";client_no_context_takeover");
+            }
+            if deflate.deflate_window_bits < MAX_WINDOW_BITS {
+                s.push_str(&format!(";server_max_window_bits={}", deflate.deflate_window_bits));
+            }
+            if deflate.inflate_window_bits < MAX_WINDOW_BITS {
+                s.push_str(&format!(";client_max_window_bits={}", deflate.inflate_window_bits));
+            }
             s.push_str(&"\r\n");
         }
+        if let Some(protocol) = protocol {
+            s.push_str("Sec-WebSocket-Protocol: ");
+            s.push_str(protocol);
+            s.push_str("\r\n");
+        }
+        // sorted for deterministic output, handshake headers can't be overridden
+        let mut keys: Vec<&String> = headers
+            .keys()
+            .filter(|k| !RESERVED_RESPONSE_HEADERS.contains(&k.to_lowercase().as_str()))
+            .collect();
+        keys.sort();
+        for key in keys {
+            s.push_str(key);
+            s.push_str(": ");
+            s.push_str(&headers[key]);
+            s.push_str("\r\n");
+        }
         s.push_str(&"\r\n");
         s
     }
 
+    // WebSocket upgrade request regardless of the version.
+    fn is_websocket_request(&self) -> bool {
+        has_token(&self.connection, "upgrade") && has_token(&self.upgrade, "websocket") && !self.key.is_empty()
+    }
+
+    // Host header is required by RFC 6455.
     fn is_valid_upgrade(&self) -> bool {
-        self.connection == "upgrade" && self.upgrade == "websocket" && self.version == "13" && self.key.len() > 0
+        self.is_websocket_request()
+            && self.version == "13"
+            && self.hosts == 1
+            && !self.host.is_empty()
+            && is_valid_key(&self.key)
     }
 
-    fn is_valid_connect(&self, key: &str) -> bool {
-        let accept = ws_accept(key);
-        self.connection == "upgrade" && self.upgrade == "websocket" && self.accept == accept
+    // Checks server upgrade response for the request with the key.
+    // Selected subprotocol, if any, must be one of the offered.
+    fn validate_connect(&self, key: &str, offered: &[String]) -> Result<(), Error> {
+        if !has_token(&self.connection, "upgrade") || !has_token(&self.upgrade, "websocket") {
+            return Err(Error::InvalidUpgradeResponse(format!(
+                "connection: '{}', upgrade: '{}'",
+                self.connection, self.upgrade
+            )));
+        }
+        if self.accept.is_empty() {
+            return Err(Error::MissingAccept);
+        }
+        let expected = ws_accept(key);
+        if self.accept != expected {
+            return Err(Error::AcceptMismatch {
+                expected,
+                actual: self.accept.clone(),
+            });
+        }
+        match self.protocols.as_slice() {
+            [] => (),
+            [protocol] if offered.contains(protocol) => (),
+            protocols => return Err(Error::ProtocolNotOffered(protocols.join(", "))),
+        }
+        Ok(())
     }
 }
 
+// Headers set by the handshake itself, application can't set them in the
+// upgrade response.
+const RESERVED_RESPONSE_HEADERS: &[&str] = &[
+    "upgrade",
+    "connection",
+    "sec-websocket-accept",
+    "sec-websocket-extensions",
+    "sec-websocket-protocol",
+];
+
+// Extension from the Sec-WebSocket-Extensions header with its parameters.
+// Names are lowercased, quotes are removed from the values.
+#[derive(Debug)]
+struct Extension {
+    name: String,
+    params: Vec<(String, Option<String>)>,
+}
+
+// Parses comma separated list of extensions, each with semicolon separated
+// parameters, e.g. `permessage-deflate; client_max_window_bits=10, x-webkit-deflate-frame`.
+fn parse_extensions(value: &str) -> Vec<Extension> {
+    let mut exts = Vec::new();
+    for ext in value.split(',') {
+        let mut parts = ext.split(';').map(|p| p.trim());
+        let name = match parts.next() {
+            Some(name) if !name.is_empty() => name.to_lowercase(),
+            _ => continue,
+        };
+        let params = parts
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.find('=') {
+                Some(i) => (
+                    p[..i].trim().to_lowercase(),
+                    Some(p[i + 1..].trim().trim_matches('"').to_owned()),
+                ),
+                None => (p.to_lowercase(), None),
+            })
+            .collect();
+        exts.push(Extension { name, params });
+    }
+    exts
+}
+
+// Server side of the permessage-deflate negotiation. Returns None if the
+// client offer can't be accepted. Server compresses each message on its own
+// (server_no_context_takeover), so it doesn't keep compressor between
+// messages for every connection. Server window is always limited to
+// max_window_bits, client's only if it supports the parameter.
+fn accept_deflate_offer(params: &[(String, Option<String>)], max_window_bits: u8) -> Option<Deflate> {
+    let mut deflate = Deflate {
+        deflate_no_context_takeover: true,
+        deflate_window_bits: max_window_bits,
+        ..Deflate::default()
+    };
+    for (i, (name, value)) in params.iter().enumerate() {
+        if params[..i].iter().any(|(n, _)| n == name) {
+            return None; // parameter repeated
+        }
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => (),
+            ("client_no_context_takeover", None) => deflate.inflate_no_context_takeover = true,
+            ("server_max_window_bits", Some(v)) => deflate.deflate_window_bits = window_bits(v)?.min(max_window_bits),
+            // client supports the parameter in the response
+            ("client_max_window_bits", None) => deflate.inflate_window_bits = max_window_bits,
+            ("client_max_window_bits", Some(v)) => deflate.inflate_window_bits = window_bits(v)?.min(max_window_bits),
+            _ => return None,
+        }
+    }
+    Some(deflate)
+}
+
+// Client side of the permessage-deflate negotiation. Returns None if the
+// server response has parameters which are not valid.
+fn connect_deflate_response(params: &[(String, Option<String>)]) -> Option<Deflate> {
+    let mut deflate = Deflate::default();
+    for (i, (name, value)) in params.iter().enumerate() {
+        if params[..i].iter().any(|(n, _)| n == name) {
+            return None; // parameter repeated
+        }
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => deflate.inflate_no_context_takeover = true,
+            ("client_no_context_takeover", None) => deflate.deflate_no_context_takeover = true,
+            ("server_max_window_bits", Some(v)) => deflate.inflate_window_bits = window_bits(v)?,
+            ("client_max_window_bits", Some(v)) => deflate.deflate_window_bits = window_bits(v)?,
+            _ => return None,
+        }
+    }
+    Some(deflate)
+}
+
+// Host matches allowed host case insensitive, allowed host without the port
+// matches any port, e.g. `example.com` matches `example.com:8080`.
+fn is_allowed_host(host: &str, allowed: &[String]) -> bool {
+    let name = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i], // ipv6 address is in brackets
+        _ => host,
+    };
+    allowed
+        .iter()
+        .any(|a| a.eq_ignore_ascii_case(host) || a.eq_ignore_ascii_case(name))
+}
+
+// Parses window bits parameter value, valid range is 8-15.
+fn window_bits(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(bits) if (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits) && !value.starts_with('0') => Some(bits),
+        _ => None,
+    }
+}
+
+// Reduces request target in absolute form, ws://host/chat?room=5, to the
+// origin form /chat?room=5 by removing scheme and authority. Empty path is /.
+fn origin_form(target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return Some(target.to_owned());
+    }
+    let rest = match target.find("://") {
+        Some(i) if i > 0 => &target[i + 3..],
+        _ => return None,
+    };
+    match rest.find(&['/', '?'][..]) {
+        Some(0) => None, // no authority
+        Some(i) if rest[i..].starts_with('/') => Some(rest[i..].to_owned()),
+        Some(i) => Some(format!("/{}", &rest[i..])),
+        None if rest.is_empty() => None,
+        None => Some("/".to_owned()),
+    }
+}
+
+// Splits request target into path and query string.
+fn split_target(target: &str) -> (String, Option<String>) {
+    match target.find('?') {
+        Some(i) => (target[..i].to_owned(), Some(target[i + 1..].to_owned())),
+        None => (target.to_owned(), None),
+    }
+}
+
+// Adds lowercased tokens of the repeated header to the comma separated list.
+fn add_tokens(list: &mut String, value: &str) {
+    if !list.is_empty() {
+        list.push_str(", ");
+    }
+    list.push_str(&value.to_lowercase());
+}
+
+// Connection and Upgrade headers are comma separated lists, e.g.
+// `Connection: keep-alive, Upgrade`.
+fn has_token(value: &str, token: &str) -> bool {
+    value.split(',').any(|t| t.trim() == token)
+}
+
+// Header which can be written to the response as is: name is a token and
+// the value has no control characters, CR or LF would end the line.
+pub fn is_valid_header(name: &str, value: &str) -> bool {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    !name.is_empty() && name.chars().all(is_tchar) && value.chars().all(|c| c == '\t' || !c.is_control())
+}
+
 fn split_header_line(line: &str) -> Option<(&str, &str)> {
     let mut splitter = line.splitn(2, ':');
     let key = splitter.next()?;
@@ -155,7 +710,7 @@ fn split_header_line(line: &str) -> Option<(&str, &str)> {
 // server would then take the SHA-1 hash of this string. This value is then
 // base64-encoded, to give the value which would be returned in the
 // |Sec-WebSocket-Accept| header field.
-fn ws_accept(key: &str) -> String {
+pub fn ws_accept(key: &str) -> String {
     const WS_MAGIC_KEY: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
     let mut hasher = Sha1::new();
     let s = key.to_string() + WS_MAGIC_KEY;
@@ -166,25 +721,42 @@ fn ws_accept(key: &str) -> String {
 }
 
 // Http header for client upgrade request to the WebSocket server.
-fn connect_header(host: &str, path: &str, key: &str, headers: Option<HashMap<String, String>>) -> String {
+fn connect_header(
+    host: &str,
+    path: &str,
+    key: &str,
+    protocols: &[String],
+    headers: Option<HashMap<String, String>>,
+    compression: bool,
+) -> String {
     let mut h = "GET ".to_owned()
         + path
         + " HTTP/1.1\r\n\
 Connection: Upgrade\r\n\
 Upgrade: websocket\r\n\
-Sec-WebSocket-Version: 13\r\n\
-Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
-Sec-WebSocket-Key: ";
+Sec-WebSocket-Version: 13\r\n";
+    if compression {
+        h.push_str("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n");
+    }
+    h.push_str("Sec-WebSocket-Key: ");
     h.push_str(key);
     h.push_str("\r\n");
     h.push_str("Host: ");
     h.push_str(host);
     h.push_str("\r\n");
+    if !protocols.is_empty() {
+        h.push_str("Sec-WebSocket-Protocol: ");
+        h.push_str(&protocols.join(", "));
+        h.push_str("\r\n");
+    }
     if let Some(headers) = headers {
-        for (key, value) in headers.iter() {
+        // sorted so the request doesn't depend on the map order
+        let mut keys: Vec<&String> = headers.keys().collect();
+        keys.sort();
+        for key in keys {
             h.push_str(key);
             h.push_str(": ");
-            h.push_str(value);
+            h.push_str(&headers[key]);
             h.push_str("\r\n");
         }
     }
@@ -192,10 +764,16 @@ Sec-WebSocket-Key: ";
     h
 }
 
+// Key must be base64 encoded 16 bytes value, that is 24 characters.
+fn is_valid_key(key: &str) -> bool {
+    key.len() == 24 && matches!(base64::decode(key), Ok(k) if k.len() == 16)
+}
+
 // Creates random key for |Sec-WebSocket-Key| http header used in client
 // connections.
-fn connect_key() -> String {
-    let buf = rand::thread_rng().gen::<[u8; 16]>();
+fn connect_key(random: &Random) -> String {
+    let mut buf = [0u8; 16];
+    random.fill(&mut buf);
     base64::encode(&buf)
 }
 
@@ -217,9 +795,11 @@ mod tests {
 
     #[test]
     fn test_connect_header() {
-        let k = connect_key();
+        let k = connect_key(&Random::default());
         assert_eq!(24, k.len());
-        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", None);
+        let k = connect_key(&Random::new(|buf| buf.iter_mut().for_each(|b| *b = 0)));
+        assert_eq!("AAAAAAAAAAAAAAAAAAAAAA==", k);
+        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", &[], None, true);
         assert_eq!(
             ch,
             "GET /ws HTTP/1.1\r\n\
@@ -232,8 +812,10 @@ Host: minus5.hr\r\n\r\n"
         );
 
         let mut headers: HashMap<String, String> = HashMap::new();
-        headers.insert("Server".to_owned(), "yarws".to_owned());
-        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", Some(headers));
+        headers.insert("User-Agent".to_owned(), "yarws".to_owned());
+        headers.insert("Authorization".to_owned(), "Bearer abc".to_owned());
+        headers.insert("Origin".to_owned(), "http://minus5.hr".to_owned());
+        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", &[], Some(headers), true);
         assert_eq!(
             ch,
             "GET /ws HTTP/1.1\r\n\
@@ -243,14 +825,74 @@ Sec-WebSocket-Version: 13\r\n\
 Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
 Sec-WebSocket-Key: mRfknYOIooirQK3OuKf54A==\r\n\
 Host: minus5.hr\r\n\
-Server: yarws\r\n\r\n"
+Authorization: Bearer abc\r\n\
+Origin: http://minus5.hr\r\n\
+User-Agent: yarws\r\n\r\n"
+        );
+
+        // without compression extensions are not offered
+        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", &[], None, false);
+        assert_eq!(
+            ch,
+            "GET /ws HTTP/1.1\r\n\
+Connection: Upgrade\r\n\
+Upgrade: websocket\r\n\
+Sec-WebSocket-Version: 13\r\n\
+Sec-WebSocket-Key: mRfknYOIooirQK3OuKf54A==\r\n\
+Host: minus5.hr\r\n\r\n"
         );
     }
 
+    #[test]
+    fn test_validate_connect() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let header = |lines: &[&str]| {
+            let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+            lines.insert(0, "HTTP/1.1 101 Switching Protocols".to_owned());
+            Header::from_lines(&lines)
+        };
+
+        let h = header(&[
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+        ]);
+        assert!(h.validate_connect(key, &[]).is_ok());
+
+        let h = header(&["Upgrade: websocket", "Connection: Upgrade"]);
+        match h.validate_connect(key, &[]) {
+            Err(Error::MissingAccept) => (),
+            other => panic!("expected missing accept got {:?}", other),
+        }
+
+        let h = header(&[
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            "Sec-WebSocket-Accept: dGFtcGVyZWQ=",
+        ]);
+        match h.validate_connect(key, &[]) {
+            Err(Error::AcceptMismatch { expected, actual }) => {
+                assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", expected);
+                assert_eq!("dGFtcGVyZWQ=", actual);
+            }
+            other => panic!("expected accept mismatch got {:?}", other),
+        }
+
+        let h = header(&[
+            "Connection: keep-alive",
+            "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+        ]);
+        match h.validate_connect(key, &[]) {
+            Err(Error::InvalidUpgradeResponse(s)) => assert_eq!("connection: 'keep-alive', upgrade: ''", s),
+            other => panic!("expected invalid upgrade response got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_parse_header() {
         test_parse_header_asserts(
             "GET /chat HTTP/1.1
+Host: minus5.hr
 Upgrade: websocket
 Connection: Upgrade
 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
@@ -258,6 +900,7 @@ Sec-WebSocket-Version: 13",
         );
         test_parse_header_asserts(
             "GET /chat HTTP/1.1
+hOsT: minus5.hr
 upgrade: websocket
 coNNection: Upgrade
 sec-webSocket-key: dGhlIHNhbXBsZSBub25jZQ==
@@ -265,11 +908,693 @@ sec-WEBSocket-VerSion: 13",
         );
     }
 
-    fn test_parse_header_asserts(req: &str) {
-        let mut header = Header::new();
-        for line in req.lines() {
-            header.append(line);
+    #[test]
+    fn test_repeated_headers() {
+        let lines: Vec<String> = [
+            "GET /chat HTTP/1.1",
+            "Sec-WebSocket-Protocol: soap",
+            "Cookie: a=1",
+            "sec-websocket-protocol: chat, superchat",
+            "cookie: b=2",
+            "Host: minus5.hr",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let header = Header::from_lines(&lines);
+        assert_eq!(3, header.lines.len());
+        let value = |key: &str| header.lines.get(key).map(|v| v.as_str());
+        assert_eq!(Some("soap, chat, superchat"), value("Sec-WebSocket-Protocol"));
+        assert_eq!(Some("a=1; b=2"), value("Cookie"));
+        assert_eq!(Some("minus5.hr"), value("Host"));
+        assert_eq!(vec!["soap", "chat", "superchat"], header.protocols);
+    }
+
+    #[test]
+    fn test_protocols() {
+        let protocols = vec!["chat".to_owned(), "superchat".to_owned()];
+        let ch = connect_header("minus5.hr", "/ws", "mRfknYOIooirQK3OuKf54A==", &protocols, None, true);
+        assert!(ch.contains("\r\nSec-WebSocket-Protocol: chat, superchat\r\n"));
+
+        let header = Header::from_lines(&[
+            "GET /chat HTTP/1.1".to_owned(),
+            "Sec-WebSocket-Protocol: soap, chat".to_owned(),
+            "sec-websocket-protocol: superchat".to_owned(),
+        ]);
+        assert_eq!(vec!["soap", "chat", "superchat"], header.protocols);
+        assert_eq!(Some("chat".to_owned()), header.select_protocol(&protocols));
+        assert_eq!(None, header.select_protocol(&["mqtt".to_owned()]));
+
+        let rsp = header.upgrade_response(Some("chat"), None, &HashMap::new());
+        assert!(rsp.ends_with("\r\nSec-WebSocket-Protocol: chat\r\n\r\n"));
+        let rsp = header.upgrade_response(None, None, &HashMap::new());
+        assert!(!rsp.contains("Sec-WebSocket-Protocol"));
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let header = Header::from_lines(&[
+            "GET /chat HTTP/1.1".to_owned(),
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==".to_owned(),
+        ]);
+        let mut headers: HashMap<String, String> = HashMap::new();
+        headers.insert("X-Request-Id".to_owned(), "42".to_owned());
+        headers.insert("Set-Cookie".to_owned(), "session=1".to_owned());
+        headers.insert("sec-websocket-accept".to_owned(), "forged".to_owned());
+        let rsp = header.upgrade_response(None, None, &headers);
+        assert!(rsp.ends_with(
+            "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+Set-Cookie: session=1\r\n\
+X-Request-Id: 42\r\n\r\n"
+        ));
+        assert!(!rsp.contains("forged"));
+        assert!(!rsp.contains("Server:"));
+    }
+
+    #[test]
+    fn test_deflate_negotiation() {
+        let header = |ext: &str| Header::from_lines(&["GET /chat HTTP/1.1".to_owned(), ext.to_owned()]);
+
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits");
+        let deflate = h.accept_deflate(MAX_WINDOW_BITS).unwrap();
+        assert!(!deflate.inflate_no_context_takeover);
+        let rsp = h.upgrade_response(None, Some(&deflate), &HashMap::new());
+        assert!(rsp.contains("\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover\r\n"));
+
+        let h =
+            header("Sec-WebSocket-Extensions: x-webkit-deflate-frame, permessage-deflate; Client_No_Context_Takeover");
+        let deflate = h.accept_deflate(MAX_WINDOW_BITS).unwrap();
+        assert!(deflate.inflate_no_context_takeover);
+        let rsp = h.upgrade_response(None, Some(&deflate), &HashMap::new());
+        assert!(rsp.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover;client_no_context_takeover\r\n"
+        ));
+
+        assert!(header("Sec-WebSocket-Extensions: x-webkit-deflate-frame")
+            .accept_deflate(MAX_WINDOW_BITS)
+            .is_none());
+        assert!(header("Sec-WebSocket-Extensions: permessage-deflate-x")
+            .accept_deflate(MAX_WINDOW_BITS)
+            .is_none());
+
+        // client reads server response
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover");
+        let deflate = h.connect_deflate().unwrap().unwrap();
+        assert!(deflate.inflate_no_context_takeover);
+        assert!(!deflate.deflate_no_context_takeover);
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; client_no_context_takeover");
+        let deflate = h.connect_deflate().unwrap().unwrap();
+        assert!(!deflate.inflate_no_context_takeover);
+        assert!(deflate.deflate_no_context_takeover);
+    }
+
+    #[test]
+    fn test_deflate_params() {
+        let header = |ext: &str| Header::from_lines(&["GET /chat HTTP/1.1".to_owned(), ext.to_owned()]);
+
+        let exts =
+            parse_extensions("permessage-deflate; client_max_window_bits=\"10\";server_no_context_takeover, x-foo");
+        assert_eq!(2, exts.len());
+        assert_eq!("permessage-deflate", exts[0].name);
+        assert_eq!(
+            vec![
+                ("client_max_window_bits".to_owned(), Some("10".to_owned())),
+                ("server_no_context_takeover".to_owned(), None)
+            ],
+            exts[0].params
+        );
+        assert_eq!("x-foo", exts[1].name);
+        assert!(exts[1].params.is_empty());
+
+        // first acceptable offer is selected
+        let h = header(
+            "Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=7, \
+            permessage-deflate; foo, permessage-deflate; server_max_window_bits=10; client_max_window_bits=12",
+        );
+        let deflate = h.accept_deflate(MAX_WINDOW_BITS).unwrap();
+        assert_eq!(10, deflate.deflate_window_bits);
+        assert_eq!(12, deflate.inflate_window_bits);
+        let rsp = h.upgrade_response(None, Some(&deflate), &HashMap::new());
+        assert!(rsp.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover;\
+            server_max_window_bits=10;client_max_window_bits=12\r\n"
+        ));
+
+        // server limits its window, and client's if it supports the parameter
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10");
+        let deflate = h.accept_deflate(9).unwrap();
+        assert_eq!((9, 15), (deflate.deflate_window_bits, deflate.inflate_window_bits));
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits");
+        let deflate = h.accept_deflate(9).unwrap();
+        assert_eq!((9, 9), (deflate.deflate_window_bits, deflate.inflate_window_bits));
+        let rsp = h.upgrade_response(None, Some(&deflate), &HashMap::new());
+        assert!(rsp.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover;\
+            server_max_window_bits=9;client_max_window_bits=9\r\n"
+        ));
+        let h = header("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=8");
+        assert_eq!(8, h.accept_deflate(12).unwrap().inflate_window_bits);
+
+        // repeated or invalid parameters
+        for ext in &[
+            "permessage-deflate; client_no_context_takeover; client_no_context_takeover",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; server_no_context_takeover=1",
+        ] {
+            assert!(header(&format!("Sec-WebSocket-Extensions: {}", ext))
+                .accept_deflate(MAX_WINDOW_BITS)
+                .is_none());
+        }
+
+        // server response
+        let h =
+            header("Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=9; client_max_window_bits=10");
+        let deflate = h.connect_deflate().unwrap().unwrap();
+        assert_eq!(9, deflate.inflate_window_bits);
+        assert_eq!(10, deflate.deflate_window_bits);
+        assert!(header("Sec-WebSocket-Version: 13").connect_deflate().unwrap().is_none());
+        assert!(
+            header("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits")
+                .connect_deflate()
+                .is_err()
+        );
+        assert!(header("Sec-WebSocket-Extensions: permessage-deflate; foo")
+            .connect_deflate()
+            .is_err());
+        assert!(header("Sec-WebSocket-Extensions: x-foo").connect_deflate().is_err());
+        assert!(
+            header("Sec-WebSocket-Extensions: permessage-deflate, permessage-deflate")
+                .connect_deflate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_request_line() {
+        let parse = |line: &str| {
+            let mut header = Header::from_lines(&[line.to_owned()]);
+            header.parse_request_line().map(|_| header)
+        };
+        let header = parse("GET /chat?room=5 HTTP/1.1").unwrap();
+        assert_eq!("GET", header.method);
+        assert_eq!("/chat", header.path);
+        assert_eq!(Some("room=5".to_owned()), header.query);
+
+        let header = parse("GET /room/42 HTTP/1.1").unwrap();
+        assert_eq!("/room/42", header.path);
+        assert_eq!(None, header.query);
+
+        // absolute form
+        let header = parse("GET ws://minus5.hr:9001/chat?room=5 HTTP/1.1").unwrap();
+        assert_eq!("/chat", header.path);
+        assert_eq!(Some("room=5".to_owned()), header.query);
+        let header = parse("GET http://minus5.hr/room/42 HTTP/1.1").unwrap();
+        assert_eq!("/room/42", header.path);
+        assert_eq!(None, header.query);
+        let header = parse("GET wss://minus5.hr HTTP/1.1").unwrap();
+        assert_eq!("/", header.path);
+        let header = parse("GET ws://minus5.hr?room=5 HTTP/1.1").unwrap();
+        assert_eq!("/", header.path);
+        assert_eq!(Some("room=5".to_owned()), header.query);
+
+        for line in &[
+            "",
+            "GET",
+            "GET /chat",
+            "GET chat HTTP/1.1",
+            "GET /chat FTP/1.1",
+            "GET /a b HTTP/1.1",
+            "GET ://minus5.hr/chat HTTP/1.1",
+            "GET ws:///chat HTTP/1.1",
+            "GET ws:// HTTP/1.1",
+        ] {
+            assert!(parse(line).is_err(), "line: {}", line);
+        }
+    }
+
+    async fn accept_response(req: &str) -> (String, Result<(), Error>) {
+        accept_response_with(req, &AcceptConfig::default()).await
+    }
+
+    async fn accept_response_with(req: &str, config: &AcceptConfig) -> (String, Result<(), Error>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut peer, local) = tokio::io::duplex(1024);
+        peer.write_all(req.as_bytes()).await.unwrap();
+        let stream = Stream::new(local);
+        let res = accept(stream, config).await.map(|_| ());
+        let mut rsp = vec![0u8; 1024];
+        let n = peer.read(&mut rsp).await.unwrap();
+        (String::from_utf8_lossy(&rsp[..n]).into_owned(), res)
+    }
+
+    #[tokio::test]
+    async fn test_accept_version() {
+        let req = |version: &str| {
+            format!(
+                "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: {}\r\n\r\n",
+                version
+            )
+        };
+        let (rsp, res) = accept_response(&req("13")).await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+
+        let (rsp, res) = accept_response(&req("8")).await;
+        assert_eq!(
+            "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            rsp
+        );
+        match res {
+            Err(Error::UnsupportedVersion(v)) => assert_eq!("8", v),
+            other => panic!("expected unsupported version got {:?}", other),
+        }
+
+        // not a websocket request
+        let (rsp, res) = accept_response("GET /chat HTTP/1.1\r\nHost: minus5.hr\r\n\r\n").await;
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\r\n", rsp);
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_key() {
+        let accept_key = |key: &str| {
+            let req = format!(
+                "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: {}\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+                key
+            );
+            async move { accept_response(&req).await }
+        };
+        // too short, not base64, too long
+        let invalid = [
+            "dGhlIHNhbXBsZQ==",
+            "dGhlIHNhbXBsZSBub25jZQ!!",
+            "dGhlIHNhbXBsZSBub25jZQ==AAAA",
+        ];
+        for key in &invalid {
+            let (rsp, res) = accept_key(key).await;
+            assert_eq!("HTTP/1.1 400 Bad Request\r\n\r\n", rsp, "key {}", key);
+            assert!(matches!(res, Err(Error::InvalidUpgradeRequest)));
+        }
+        let (rsp, res) = accept_key("dGhlIHNhbXBsZSBub25jZQ==").await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(is_valid_key(&connect_key(&Random::default())));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("dGhlIHNhbXBsZQ=="));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZQ!!"));
+        // 24 characters, but 17 bytes
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZXM="));
+    }
+
+    #[tokio::test]
+    async fn test_accept_method() {
+        let req = "POST /chat HTTP/1.1\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n";
+        let (rsp, res) = accept_response(req).await;
+        assert_eq!("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n\r\n", rsp);
+        match res {
+            Err(Error::MethodNotAllowed(m)) => assert_eq!("POST", m),
+            other => panic!("expected method not allowed got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_connection_tokens() {
+        let header = Header::from_lines(&[
+            "GET /chat HTTP/1.1".to_owned(),
+            "Host: minus5.hr".to_owned(),
+            "Connection: keep-alive, Upgrade".to_owned(),
+            "Upgrade: h2c, WebSocket".to_owned(),
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==".to_owned(),
+            "Sec-WebSocket-Version: 13".to_owned(),
+        ]);
+        assert!(header.is_valid_upgrade());
+
+        let header = Header::from_lines(&[
+            "GET /chat HTTP/1.1".to_owned(),
+            "Connection: keep-alive, upgrades".to_owned(),
+            "Upgrade: websocket".to_owned(),
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==".to_owned(),
+            "Sec-WebSocket-Version: 13".to_owned(),
+        ]);
+        assert!(!header.is_valid_upgrade());
+
+        let upgrade = |lines: &[&str]| {
+            let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+            lines.insert(0, "GET /chat HTTP/1.1".to_owned());
+            lines.push("Host: minus5.hr".to_owned());
+            lines.push("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==".to_owned());
+            lines.push("Sec-WebSocket-Version: 13".to_owned());
+            Header::from_lines(&lines).is_valid_upgrade()
+        };
+        assert!(upgrade(&["Connection: Upgrade", "Upgrade: WebSocket"]));
+        assert!(upgrade(&["Connection: Upgrade", "Upgrade: websocket, h2c"]));
+        assert!(upgrade(&["Connection: Upgrade", "Upgrade: websocket "]));
+        // tokens from the repeated headers
+        assert!(upgrade(&[
+            "Connection: keep-alive",
+            "Connection: Upgrade",
+            "Upgrade: h2c",
+            "Upgrade: websocket"
+        ]));
+        assert!(!upgrade(&["Connection: Upgrade", "Upgrade: websockets, h2c"]));
+    }
+
+    #[tokio::test]
+    async fn test_accept_keep_alive_upgrade() {
+        let (rsp, res) = accept_response(
+            "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: keep-alive, Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_accept_check() {
+        let req = |origin: &str| {
+            format!(
+                "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Origin: {}\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+                origin
+            )
+        };
+        let config = AcceptConfig {
+            check: Some(Box::new(|h| {
+                h.get("Origin").map(|o| o == "https://minus5.hr") == Some(true)
+            })),
+            ..AcceptConfig::default()
+        };
+
+        let (rsp, res) = accept_response_with(&req("https://minus5.hr"), &config).await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+
+        let (rsp, res) = accept_response_with(&req("https://evil.com"), &config).await;
+        assert_eq!("HTTP/1.1 403 Forbidden\r\n\r\n", rsp);
+        match res {
+            Err(Error::RequestRejected) => (),
+            other => panic!("expected request rejected got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_host() {
+        let accept_host = |host: &'static str, allowed_hosts: Vec<String>| async move {
+            let req = format!(
+                "GET /chat HTTP/1.1\r\n\
+{}\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+                host
+            );
+            let config = AcceptConfig {
+                allowed_hosts,
+                ..AcceptConfig::default()
+            };
+            accept_response_with(&req, &config).await
+        };
+
+        // host is required
+        let (rsp, res) = accept_host("", Vec::new()).await;
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\r\n", rsp);
+        assert!(res.is_err());
+
+        // only one host is allowed (RFC 7230 5.4)
+        let allowed = vec!["minus5.hr".to_owned()];
+        let (rsp, res) = accept_host("Host: evil.com\r\nHost: minus5.hr\r\n", allowed).await;
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\r\n", rsp);
+        assert!(res.is_err());
+
+        let allowed = vec!["minus5.hr".to_owned(), "[::1]:9001".to_owned()];
+        let (rsp, res) = accept_host("Host: Minus5.hr:8080\r\n", allowed.clone()).await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+
+        let (rsp, res) = accept_host("Host: evil.com\r\n", allowed).await;
+        assert_eq!("HTTP/1.1 421 Misdirected Request\r\n\r\n", rsp);
+        match res {
+            Err(Error::HostNotAllowed(host)) => assert_eq!("evil.com", host),
+            other => panic!("expected host not allowed got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_is_allowed_host() {
+        let allowed = vec!["minus5.hr".to_owned(), "localhost:9001".to_owned(), "[::1]".to_owned()];
+        for host in &["minus5.hr", "MINUS5.hr:443", "localhost:9001", "[::1]", "[::1]:9001"] {
+            assert!(is_allowed_host(host, &allowed), "{}", host);
+        }
+        for host in &["minus5.hr.evil.com", "localhost", "localhost:9002", "[::2]", "::1"] {
+            assert!(!is_allowed_host(host, &allowed), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_authorize() {
+        let req = |auth: &str| {
+            format!(
+                "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+{}\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+                auth
+            )
+        };
+        let authorize: Box<Authorize> = Box::new(|headers| {
+            Box::pin(async move {
+                match headers.get("Authorization").map(|v| v.as_str()) {
+                    Some("Bearer secret") => Ok(()),
+                    _ => {
+                        let mut headers = HashMap::new();
+                        headers.insert("WWW-Authenticate".to_owned(), "Bearer".to_owned());
+                        Err((401, headers))
+                    }
+                }
+            })
+        });
+
+        let config = AcceptConfig {
+            authorize: Some(authorize),
+            ..AcceptConfig::default()
+        };
+
+        let (rsp, res) = accept_response_with(&req("Authorization: Bearer secret\r\n"), &config).await;
+        assert!(rsp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.is_ok());
+
+        let (rsp, res) = accept_response_with(&req(""), &config).await;
+        assert_eq!("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n\r\n", rsp);
+        match res {
+            Err(Error::RequestRejected) => (),
+            other => panic!("expected request rejected got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejected_response() {
+        let mut headers = HashMap::new();
+        headers.insert("Retry-After".to_owned(), "120".to_owned());
+        headers.insert("X-Split".to_owned(), "a\r\nSet-Cookie: b=c".to_owned());
+        headers.insert("X-Bad\r\nName".to_owned(), "a".to_owned());
+        headers.insert("X Space".to_owned(), "a".to_owned());
+        assert_eq!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\n\r\n",
+            rejected_response(503, &headers)
+        );
+        // not an error status
+        for status in &[101, 200, 302, 600] {
+            assert_eq!(
+                "HTTP/1.1 500 Internal Server Error\r\n\r\n",
+                rejected_response(*status, &HashMap::new())
+            );
+        }
+        assert!(is_valid_header("X-Id", "a\tb c"));
+        assert!(!is_valid_header("", "a"));
+        assert!(!is_valid_header("X:Id", "a"));
+        assert!(!is_valid_header("X-Id", "a\nb"));
+    }
+
+    #[tokio::test]
+    async fn test_accept_browser_deflate_offer() {
+        // offer sent by Chrome and Firefox
+        let req = "GET /chat HTTP/1.1\r\n\
+Host: minus5.hr\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n";
+        let (rsp, res) = accept_response(req).await;
+        assert!(res.is_ok());
+        // full window of the client is the default, nothing to echo
+        assert!(rsp.contains("\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover\r\n"));
+
+        let config = AcceptConfig {
+            max_window_bits: 10,
+            ..AcceptConfig::default()
+        };
+        let (rsp, res) = accept_response_with(req, &config).await;
+        assert!(res.is_ok());
+        assert!(rsp.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate;server_no_context_takeover;\
+            server_max_window_bits=10;client_max_window_bits=10\r\n"
+        ));
+    }
+
+    async fn connect_response(rsp: &str) -> Result<(), Error> {
+        use tokio::io::AsyncWriteExt;
+        let (mut peer, local) = tokio::io::duplex(4096);
+        peer.write_all(rsp.as_bytes()).await.unwrap();
+        let url = super::super::parse_url("ws://minus5.hr/chat").unwrap();
+        let config = ConnectConfig::default();
+        connect(Stream::new(local), &url, &config, &Random::default())
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_connect_failed() {
+        match connect_response("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic\r\n\r\n").await {
+            Err(Error::HandshakeFailed { status, headers }) => {
+                assert_eq!(401, status);
+                assert_eq!(Some(&"Basic".to_owned()), headers.get("WWW-Authenticate"));
+            }
+            other => panic!("expected handshake failed got {:?}", other),
+        }
+        // upgraded but without accept header
+        match connect_response("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+            .await
+        {
+            Err(Error::MissingAccept) => (),
+            other => panic!("expected missing accept got {:?}", other),
+        }
+        match connect_response("garbage\r\n\r\n").await {
+            Err(Error::HandshakeFailed { status, .. }) => assert_eq!(0, status),
+            other => panic!("expected handshake failed got {:?}", other),
+        }
+    }
+
+    // Returns subprotocol of the connection when the server selects protocol.
+    async fn connect_protocol(offered: &[&str], protocol: Option<&str>) -> Result<Option<String>, Error> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut peer, local) = tokio::io::duplex(4096);
+        let url = super::super::parse_url("ws://minus5.hr/chat").unwrap();
+        let config = ConnectConfig {
+            protocols: offered.iter().map(|p| p.to_string()).collect(),
+            ..ConnectConfig::default()
+        };
+        let random = Random::default();
+        let connect = connect(Stream::new(local), &url, &config, &random);
+        let respond = async {
+            let mut req = vec![0u8; 1024];
+            let n = peer.read(&mut req).await.unwrap();
+            let req = String::from_utf8_lossy(&req[..n]).into_owned();
+            let key = req.lines().find_map(|l| l.strip_prefix("Sec-WebSocket-Key: ")).unwrap();
+            let mut rsp = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+                ws_accept(key)
+            );
+            if let Some(protocol) = protocol {
+                rsp.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+            }
+            rsp.push_str("\r\n");
+            peer.write_all(rsp.as_bytes()).await.unwrap();
+        };
+        let (hs, _) = tokio::join!(connect, respond);
+        hs.map(|hs| hs.protocol)
+    }
+
+    #[tokio::test]
+    async fn test_connect_protocol() {
+        let offered = ["chat", "superchat"];
+        let protocol = connect_protocol(&offered, Some("superchat")).await.unwrap();
+        assert_eq!(Some("superchat".to_owned()), protocol);
+        assert_eq!(None, connect_protocol(&offered, None).await.unwrap());
+        match connect_protocol(&offered, Some("mqtt")).await {
+            Err(Error::ProtocolNotOffered(protocol)) => assert_eq!("mqtt", protocol),
+            other => panic!("expected protocol not offered got {:?}", other.map(|_| ())),
+        }
+        // server must select only one
+        match connect_protocol(&offered, Some("chat, superchat")).await {
+            Err(Error::ProtocolNotOffered(protocol)) => assert_eq!("chat, superchat", protocol),
+            other => panic!("expected protocol not offered got {:?}", other.map(|_| ())),
+        }
+        // nothing offered
+        assert!(connect_protocol(&[], Some("chat")).await.is_err());
+    }
+
+    async fn proxy_response(rsp: &str, auth: Option<&str>) -> (String, Result<(), Error>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut peer, local) = tokio::io::duplex(1024);
+        peer.write_all(rsp.as_bytes()).await.unwrap();
+        let mut stream = Stream::new(local);
+        let res = proxy_connect(&mut stream, "minus5.hr:443", auth).await;
+        let mut req = vec![0u8; 1024];
+        let n = peer.read(&mut req).await.unwrap();
+        (String::from_utf8_lossy(&req[..n]).into_owned(), res)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_connect() {
+        let (req, res) = proxy_response("HTTP/1.1 200 Connection established\r\n\r\n", None).await;
+        assert_eq!("CONNECT minus5.hr:443 HTTP/1.1\r\nHost: minus5.hr:443\r\n\r\n", req);
+        assert!(res.is_ok());
+
+        let (req, res) = proxy_response("HTTP/1.0 200 OK\r\nVia: proxy\r\n\r\n", Some("dXNlcjpwYXNz")).await;
+        assert_eq!(
+            "CONNECT minus5.hr:443 HTTP/1.1\r\nHost: minus5.hr:443\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+            req
+        );
+        assert!(res.is_ok());
+
+        let (_, res) = proxy_response("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n", None).await;
+        match res {
+            Err(Error::ProxyConnectFailed(status)) => assert_eq!("HTTP/1.1 407 Proxy Authentication Required", status),
+            other => panic!("expected proxy connect failed got {:?}", other),
         }
+    }
+
+    fn test_parse_header_asserts(req: &str) {
+        let lines: Vec<String> = req.lines().map(|l| l.to_owned()).collect();
+        let mut header = Header::from_lines(&lines);
+        assert!(header.parse_request_line().is_ok());
+        assert_eq!("/chat", header.path);
         assert!(header.is_valid_upgrade());
         assert_eq!(header.connection, "upgrade");
         assert_eq!(header.upgrade, "websocket");
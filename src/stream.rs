@@ -1,6 +1,9 @@
+use super::Error;
+use std::time::Duration;
 use tokio;
 use tokio::io::{BufReader, AsyncRead, AsyncWrite, AsyncReadExt, AsyncBufReadExt, AsyncWriteExt};
 use tokio::io;
+use tokio::net::TcpStream;
 
 pub struct Stream<R, W> {
     pub rh: ReadHalf<R>,
@@ -29,6 +32,54 @@ where
     }
 }
 
+// Options of the tcp connection, set on connected and accepted streams.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    pub nodelay: bool,
+    // keepalive idle time and interval between probes
+    pub keepalive: Option<(Duration, Duration)>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some((idle, interval)) = self.keepalive {
+            let params = socket2::TcpKeepalive::new().with_time(idle);
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "windows"
+            ))]
+            let params = params.with_interval(interval);
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "windows"
+            )))]
+            let _ = interval;
+            socket2::SockRef::from(stream).set_tcp_keepalive(&params)?;
+        }
+        Ok(())
+    }
+}
+
+// Same buffered reader is used for the http header and then the frames, so
+// the frame bytes which arrive together with the header are not lost.
 pub struct ReadHalf<R> {
     inner: BufReader<R>,
 }
@@ -43,16 +94,28 @@ where
         }
     }
 
-    pub async fn http_header(&mut self) -> Result<Vec<String>, io::Error> {
+    // Reads http header lines until the empty line. Errors with
+    // HeaderTooLarge if the header has more than max_lines or more than
+    // max_size bytes, without buffering the rest of it.
+    pub async fn http_header(&mut self, max_lines: usize, max_size: usize) -> Result<Vec<String>, Error> {
         let mut res: Vec<String> = Vec::new();
+        let mut size = 0;
         loop {
             let mut line = String::new();
             // TODO: sta ako ovdje nikada nista ne posalje blokira mi thread !!!
-            match self.inner.read_line(&mut line).await? {
+            let limit = (max_size - size) as u64;
+            let n = (&mut self.inner).take(limit).read_line(&mut line).await?;
+            size += n;
+            match n {
+                0 if limit == 0 => return Err(Error::HeaderTooLarge),
                 0 => break, // eof
                 2 => break, // empty line \r\n = end of header line
+                _n if !line.ends_with('\n') && size == max_size => return Err(Error::HeaderTooLarge),
                 _n => res.push(line.trim().to_owned()),
             }
+            if res.len() > max_lines {
+                return Err(Error::HeaderTooLarge);
+            }
         }
         Ok(res)
     }
@@ -67,6 +130,10 @@ where
     pub async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.inner.read_exact(buf).await
     }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).await
+    }
 }
 
 pub struct WriteHalf<W> {
@@ -92,4 +159,71 @@ where
         }
         Ok(())
     }
+
+    pub async fn shutdown(&mut self) -> Result<(), io::Error> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn http_header_limits() {
+        let header = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut rh = ReadHalf::new(header.as_bytes());
+        let lines = rh.http_header(2, 1024).await.unwrap();
+        assert_eq!(vec!["GET / HTTP/1.1", "Host: localhost"], lines);
+
+        let mut rh = ReadHalf::new(header.as_bytes());
+        assert!(rh.http_header(1, 1024).await.is_err());
+        let mut rh = ReadHalf::new(header.as_bytes());
+        assert!(rh.http_header(2, 20).await.is_err());
+
+        // stops reading after max_lines
+        let header = "X-Header: value\r\n".repeat(10_000);
+        let mut rh = ReadHalf::new(header.as_bytes());
+        assert!(rh.http_header(100, 1024 * 1024).await.is_err());
+        assert!(rh.inner.get_ref().len() > header.len() / 2);
+
+        // stops reading long line after max_size
+        let header = "X".repeat(1024 * 1024);
+        let mut rh = ReadHalf::new(header.as_bytes());
+        assert!(rh.http_header(100, 16 * 1024).await.is_err());
+        assert!(rh.inner.get_ref().len() > header.len() / 2);
+    }
+
+    #[tokio::test]
+    async fn http_header_leaves_rest_buffered() {
+        // first frame arrives together with the header
+        let data = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\x81\x02hi";
+        let mut rh = ReadHalf::new(&data[..]);
+        assert_eq!(2, rh.http_header(100, 1024).await.unwrap().len());
+        assert_eq!(b"\x81\x02hi".to_vec(), rh.read_to_end().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tcp_config() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        TcpConfig::default().apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+
+        let config = TcpConfig {
+            nodelay: false,
+            keepalive: Some((Duration::from_secs(30), Duration::from_secs(5))),
+        };
+        config.apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        let sock = socket2::SockRef::from(&stream);
+        assert!(sock.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(Duration::from_secs(30), sock.tcp_keepalive_time().unwrap());
+            assert_eq!(Duration::from_secs(5), sock.tcp_keepalive_interval().unwrap());
+        }
+    }
 }
@@ -1,15 +1,20 @@
+use super::codec::{
+    self, mask, Decoder, Deflate, Deflater, Frame, FrameWriter, Opcode, Random, DEFLATE_LEVEL, MAX_MESSAGE_SIZE,
+};
 use super::stream;
 use super::stream::Stream;
-use super::Error;
-use inflate::inflate_bytes;
-use rand::Rng;
+use super::{DeflateStrategy, Error, Event, Overflow, Stats};
 use slog::Logger;
-use std::fmt;
-use std::str;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio;
 use tokio::{spawn, io};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{timeout, Interval};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Debug)]
@@ -17,19 +22,76 @@ use tokio::io::{AsyncRead, AsyncWrite};
 pub enum Msg {
     Binary(Vec<u8>),
     Text(String),
-    Close(u16),
+    // Binary message with the payload shared between many sockets. Payload
+    // is not copied for each socket.
+    Shared(Arc<[u8]>),
+    // Status and reason, None for the close without payload.
+    Close(Option<(u16, String)>),
     Ping(Vec<u8>),
     Pong(Vec<u8>),
+    // Part of the binary message streamed by the application. First fragment
+    // is sent as binary frame, others as continuations, the last one has fin
+    // set. Fragments are never compressed.
+    Fragment { first: bool, fin: bool, payload: Vec<u8> },
+    // Connection failed without the close handshake. Sent by the Reader as
+    // the last message instead of the close, never sent to the peer.
+    Failed(Timeout),
+    // Ping sent by the application waiting for the pong with the same
+    // payload, reply is the round trip time.
+    Probe(Vec<u8>, oneshot::Sender<Duration>),
+}
+
+// Timeout which failed the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    Pong,
+    Read,
+    Write,
+}
+
+impl From<Timeout> for Error {
+    fn from(f: Timeout) -> Self {
+        match f {
+            Timeout::Pong => Error::PongTimeout,
+            Timeout::Read => Error::ReadTimeout,
+            Timeout::Write => Error::WriteTimeout,
+        }
+    }
 }
 
 impl Msg {
+    // Close with the status, status 0 is the close without payload.
+    pub fn close(status: u16, reason: String) -> Msg {
+        match status {
+            0 => Msg::Close(None),
+            _ => Msg::Close(Some((status, reason))),
+        }
+    }
+
+    // Status of the close, 0 if it has no payload or it is not close.
+    pub fn close_status(&self) -> u16 {
+        match self {
+            Msg::Close(Some((status, _))) => *status,
+            _ => 0,
+        }
+    }
+
     pub fn clone(&self) -> Msg {
         match self {
             Msg::Text(text) => Msg::Text(text.clone()),
             Msg::Binary(payload) => Msg::Binary(payload.clone()),
-            Msg::Close(status) => Msg::Close(*status),
+            Msg::Shared(payload) => Msg::Shared(payload.clone()),
+            Msg::Close(close) => Msg::Close(close.clone()),
             Msg::Ping(payload) => Msg::Ping(payload.clone()),
             Msg::Pong(payload) => Msg::Pong(payload.clone()),
+            Msg::Fragment { first, fin, payload } => Msg::Fragment {
+                first: *first,
+                fin: *fin,
+                payload: payload.clone(),
+            },
+            Msg::Failed(failure) => Msg::Failed(*failure),
+            // only one can wait for the reply, clone is the plain ping
+            Msg::Probe(payload, _) => Msg::Ping(payload.clone()),
         }
     }
 
@@ -37,25 +99,37 @@ impl Msg {
         match self {
             Msg::Text(text) => Some(super::Msg::Text(text)),
             Msg::Binary(payload) => Some(super::Msg::Binary(payload)),
-            _ => None,
+            Msg::Shared(payload) => Some(super::Msg::Binary(payload.to_vec())),
+            Msg::Ping(payload) => Some(super::Msg::Ping(payload)),
+            Msg::Pong(payload) => Some(super::Msg::Pong(payload)),
+            Msg::Close(..) | Msg::Fragment { .. } | Msg::Failed(_) | Msg::Probe(..) => None,
         }
     }
 
-    fn into_raw(self, client: bool) -> Vec<u8> {
-        let w = FrameWriter::new(client);
+    fn into_raw(self, w: &mut FrameWriter) -> Vec<u8> {
         match self {
             Msg::Binary(payload) => w.binary(payload),
+            Msg::Shared(payload) => w.data(Opcode::Binary, &payload),
             Msg::Text(text) => w.text(text),
-            Msg::Close(status) => w.close(status),
-            Msg::Ping(payload) => w.ping(payload),
+            // echo of the locally reported status goes without status
+            Msg::Close(Some((STATUS_ABNORMAL_CLOSURE, _))) => w.close(None),
+            Msg::Close(close) => w.close(close),
+            Msg::Ping(payload) | Msg::Probe(payload, _) => w.ping(payload),
             Msg::Pong(payload) => w.pong(payload),
+            Msg::Fragment { first, fin, payload } => w.fragment(first, fin, payload),
+            Msg::Failed(_) => Vec::new(),
         }
     }
 
+    // Application data, the only messages which the outbox may drop.
+    fn is_data(&self) -> bool {
+        matches!(self, Msg::Text(_) | Msg::Binary(_) | Msg::Shared(_))
+    }
+
     #[allow(dead_code)]
     fn is_close(&self) -> bool {
         match self {
-            Msg::Close(_) => true,
+            Msg::Close(..) => true,
             _ => false,
         }
     }
@@ -63,19 +137,388 @@ impl Msg {
     #[allow(dead_code)]
     fn kind(&self) -> &'static str {
         match self {
-            Msg::Binary(_) => "binary",
+            Msg::Binary(_) | Msg::Shared(_) => "binary",
             Msg::Text(_) => "text",
-            Msg::Close(_) => "close",
-            Msg::Ping(_) => "ping",
+            Msg::Close(..) => "close",
+            Msg::Ping(_) | Msg::Probe(..) => "ping",
             Msg::Pong(_) => "pong",
+            Msg::Fragment { .. } => "fragment",
+            Msg::Failed(_) => "failed",
+        }
+    }
+}
+
+// Connection options set by the application.
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Maximum size of the incoming message payload. Checked for each frame
+    // before allocating payload buffer and for the reassembled fragments.
+    // Compressed message is checked again while it is inflated.
+    pub max_message_size: usize,
+    // Maximum number of frames of the fragmented message, empty
+    // continuation frames included.
+    pub max_fragments_per_message: Option<usize>,
+    // Text with invalid utf-8 closes the connection with 1007. When false it
+    // is delivered with invalid sequences replaced, that is not conformant.
+    pub strict_utf8: bool,
+    // Maximum number of control frames received in one second, connection
+    // is closed with policy violation when the peer sends more.
+    pub max_control_rate: Option<u32>,
+    // Reader replies to the ping with the pong without involving the
+    // application. Otherwise pings are passed to the application.
+    pub auto_pong: bool,
+    // Writer sends ping after this interval without outbound traffic.
+    // Connection is closed when nothing is received for two intervals.
+    pub keepalive_interval: Option<Duration>,
+    // Connection fails when the pong with the payload of the sent ping
+    // doesn't arrive in time.
+    pub pong_timeout: Option<Duration>,
+    // Outbound data messages larger than this are split into fragments.
+    pub max_frame_size: Option<usize>,
+    // Compression level (0-10) and strategy of the outbound messages when
+    // permessage-deflate is negotiated.
+    pub deflate_level: u8,
+    pub deflate_strategy: DeflateStrategy,
+    // Pong messages are passed to the application.
+    pub control_msgs: bool,
+    // Capacity of the channels between the application and Reader/Writer.
+    pub buffer_size: usize,
+    // What sending of the data message does when the outbound buffer is
+    // full. Policies other than Block queue the messages in the outbox.
+    pub overflow: Overflow,
+    pub outbox: Outbox,
+    // After sending close Writer waits that long for the peer's close before
+    // closing the stream.
+    pub close_timeout: Duration,
+    // Status of the close sent when the application drops the socket, 0 for
+    // close without status.
+    pub drop_close_status: u16,
+    // Limit for reading the whole frame, from its first byte. Waiting for
+    // the next frame is not limited.
+    pub read_timeout: Option<Duration>,
+    // Limit for writing each frame to the stream.
+    pub write_timeout: Option<Duration>,
+    // Writer joins messages already waiting in the application channel
+    // into one write to the stream.
+    pub coalesce_writes: bool,
+    // Application channel for the connection lifecycle events.
+    pub events: Option<Events>,
+    // Counters updated by the Reader and Writer.
+    pub stats: Arc<Stats>,
+    // Stops the Reader and Writer without the close handshake.
+    pub abort: Abort,
+    // Source of the masking keys and the handshake key.
+    pub random: Random,
+}
+
+impl Config {
+    // Config of the single connection, events are tagged with its id and
+    // it gets its own stats.
+    pub fn for_conn(&self, conn_id: usize) -> Config {
+        let mut config = self.clone();
+        config.stats = Arc::default();
+        config.abort = Abort::default();
+        config.outbox = Outbox::new(config.overflow, config.buffer_size);
+        if let Some(events) = &mut config.events {
+            events.conn_id = conn_id;
+        }
+        config
+    }
+}
+
+// Sends lifecycle events to the application. Events are dropped if the
+// channel is full, connection never waits for the application.
+#[derive(Debug, Clone)]
+pub struct Events {
+    pub tx: Sender<Event>,
+    pub conn_id: usize,
+}
+
+impl Events {
+    fn open(&self) {
+        self.send(Event::Open { id: self.conn_id });
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        self.send(Event::Close {
+            id: self.conn_id,
+            code,
+            reason: reason.to_owned(),
+        });
+    }
+
+    fn error(&self, error: Error) {
+        self.send(Event::Error {
+            id: self.conn_id,
+            error,
+        });
+    }
+
+    fn send(&self, event: Event) {
+        self.tx.try_send(event).unwrap_or_default();
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_fragments_per_message: None,
+            strict_utf8: true,
+            max_control_rate: None,
+            auto_pong: true,
+            keepalive_interval: None,
+            pong_timeout: None,
+            max_frame_size: None,
+            deflate_level: DEFLATE_LEVEL,
+            deflate_strategy: DeflateStrategy::Default,
+            control_msgs: false,
+            buffer_size: 32,
+            overflow: Overflow::Block,
+            outbox: Outbox::default(),
+            close_timeout: Duration::from_secs(5),
+            drop_close_status: 0,
+            read_timeout: None,
+            write_timeout: None,
+            coalesce_writes: false,
+            events: None,
+            stats: Arc::default(),
+            abort: Abort::default(),
+            random: Random::default(),
+        }
+    }
+}
+
+// Tears down the connection from the application side. Aborted flag stays
+// set, so the tasks which start waiting later stop too.
+#[derive(Debug, Clone, Default)]
+pub struct Abort {
+    aborted: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Abort {
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    // Completes when aborted. Future is registered before checking the flag
+    // so the notification can't be missed.
+    pub async fn aborted(&self) {
+        let notified = self.notify.notified();
+        if self.aborted.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+// Outbound data messages of the application when the overflow policy is
+// not Block. Send never waits, the message is dropped when the queue is full.
+// Writer takes the messages from the front.
+#[derive(Debug, Clone, Default)]
+pub struct Outbox {
+    queue: Arc<Mutex<Queue>>,
+    ready: Arc<Notify>,
+    overflow: Overflow,
+    capacity: usize,
+}
+
+impl Outbox {
+    pub fn new(overflow: Overflow, capacity: usize) -> Outbox {
+        Outbox {
+            overflow,
+            capacity,
+            ..Outbox::default()
+        }
+    }
+
+    // Queues the data message by the overflow policy, other messages and all
+    // messages with Block go through the application channel. Returns false
+    // if a message is dropped.
+    pub async fn send(&self, tx: &Sender<Msg>, msg: Msg) -> Result<bool, mpsc::error::SendError<Msg>> {
+        if self.overflow == Overflow::Block || !msg.is_data() {
+            return tx.send(msg).await.map(|_| true);
+        }
+        if tx.is_closed() {
+            return Err(mpsc::error::SendError(msg));
+        }
+        Ok(self.push(msg))
+    }
+
+    // Only the data messages count toward the capacity and only they are
+    // dropped. Appended close, ping or fragment must reach the peer.
+    fn push(&self, msg: Msg) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let full = queue.data >= self.capacity;
+        match (full, self.overflow) {
+            (false, _) => queue.push(msg),
+            (true, Overflow::DropOldest) => {
+                queue.drop_oldest();
+                queue.push(msg);
+            }
+            (true, _) => (), // new message is dropped
+        }
+        drop(queue);
+        self.ready.notify_one();
+        !full
+    }
+
+    // Appends the message from the application channel after the queued
+    // ones, regardless of the capacity, so it keeps its order.
+    fn append(&self, msg: Msg) {
+        self.queue.lock().unwrap().push(msg);
+        self.ready.notify_one();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().msgs.is_empty()
+    }
+
+    fn try_pop(&self) -> Option<Msg> {
+        self.queue.lock().unwrap().pop()
+    }
+
+    // Waits for the message. Notification which arrives between the check
+    // and the wait is stored as a permit.
+    async fn pop(&self) -> Msg {
+        loop {
+            if let Some(msg) = self.try_pop() {
+                return msg;
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+// Messages of the outbox and the number of data messages among them.
+#[derive(Debug, Default)]
+struct Queue {
+    msgs: VecDeque<Msg>,
+    data: usize,
+}
+
+impl Queue {
+    fn push(&mut self, msg: Msg) {
+        if msg.is_data() {
+            self.data += 1;
+        }
+        self.msgs.push_back(msg);
+    }
+
+    fn pop(&mut self) -> Option<Msg> {
+        let msg = self.msgs.pop_front()?;
+        if msg.is_data() {
+            self.data -= 1;
+        }
+        Some(msg)
+    }
+
+    // Removes the first data message, skipping the appended ones.
+    fn drop_oldest(&mut self) {
+        if let Some(i) = self.msgs.iter().position(Msg::is_data) {
+            self.msgs.remove(i);
+            self.data -= 1;
+        }
+    }
+}
+
+// Application ping waiting for the pong: payload, when it was written and
+// the reply channel.
+type Probe = (Vec<u8>, Instant, oneshot::Sender<Duration>);
+
+// Connection state shared between Reader and Writer.
+struct Shared {
+    last_read: Mutex<Instant>, // when was the last frame received
+    closed: Notify,            // signals Reader to stop reading
+    write_closed: Notify,      // signals Writer to stop writing
+    read_closed: Notify,       // Reader signals that it is finished
+    close_sent: AtomicBool,    // Writer started the closing handshake
+    // payload and time of the first ping still waiting for pong
+    ping: Mutex<Option<(Vec<u8>, Instant)>>,
+    // application pings waiting for pongs
+    probes: Mutex<Vec<Probe>>,
+    // set by the Writer when it stops the Reader because of the failure
+    failure: Mutex<Option<Timeout>>,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Shared {
+            last_read: Mutex::new(Instant::now()),
+            closed: Notify::new(),
+            write_closed: Notify::new(),
+            read_closed: Notify::new(),
+            close_sent: AtomicBool::new(false),
+            ping: Mutex::new(None),
+            probes: Mutex::new(Vec::new()),
+            failure: Mutex::new(None),
+        }
+    }
+
+    // Starts waiting for the pong unless already waiting for the previous.
+    fn ping_sent(&self, payload: &[u8]) {
+        let mut ping = self.ping.lock().unwrap();
+        if ping.is_none() {
+            *ping = Some((payload.to_vec(), Instant::now()));
+        }
+    }
+
+    // Registers application ping, drops those the application stopped
+    // waiting for.
+    fn probe_sent(&self, payload: &[u8], reply: oneshot::Sender<Duration>) {
+        let mut probes = self.probes.lock().unwrap();
+        probes.retain(|(_, _, reply)| !reply.is_closed());
+        probes.push((payload.to_vec(), Instant::now(), reply));
+    }
+
+    // Stops waiting if the pong matches the ping, stale pongs are ignored.
+    // Replies to the first application ping with the same payload.
+    fn pong_received(&self, payload: &[u8]) {
+        let mut ping = self.ping.lock().unwrap();
+        if matches!(&*ping, Some((p, _)) if p == payload) {
+            *ping = None;
+        }
+        let mut probes = self.probes.lock().unwrap();
+        if let Some(i) = probes.iter().position(|(p, _, _)| p == payload) {
+            let (_, sent, reply) = probes.remove(i);
+            reply.send(sent.elapsed()).unwrap_or_default();
+        }
+    }
+
+    fn ping_sent_at(&self) -> Option<Instant> {
+        self.ping.lock().unwrap().as_ref().map(|(_, sent)| *sent)
+    }
+
+    // Stops the Reader, it reports the failure to the application. Read
+    // timeout stops the Writer too, there is no one to wait for the close.
+    fn fail(&self, failure: Timeout) {
+        if let Timeout::Read = failure {
+            self.write_closed.notify_one();
         }
+        *self.failure.lock().unwrap() = Some(failure);
+        self.closed.notify_one();
+    }
+
+    fn close_sent(&self) -> bool {
+        self.close_sent.load(Ordering::SeqCst)
+    }
+
+    fn touch(&self) {
+        *self.last_read.lock().unwrap() = Instant::now();
+    }
+
+    fn since_last_read(&self) -> Duration {
+        self.last_read.lock().unwrap().elapsed()
     }
 }
 
 pub async fn start<R, W>(
     stream: Stream<R, W>,
-    mask_frames: bool,
-    deflate_supported: bool,
+    is_server: bool,
+    deflate: Option<Deflate>,
+    config: Config,
     log: Logger,
 ) -> (Receiver<Msg>, Sender<Msg>)
 where
@@ -83,583 +526,2155 @@ where
     W: AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
 {
     trace!(log, "open");
+    if let Some(events) = &config.events {
+        events.open();
+    }
     // rx receive end, tx transmit end
-    let app_tx = Writer::spawn(stream.wh, mask_frames, log.clone()); // handle write half
-    let socket_rx = Reader::spawn(stream.rh, deflate_supported, log); // handle read half
+    // client masks outgoing frames, server expects masked incoming frames
+    let shared = Arc::new(Shared::new());
+    let (app_tx, control_tx) = Writer::spawn(
+        stream.wh,
+        !is_server,
+        deflate,
+        config.clone(),
+        shared.clone(),
+        log.clone(),
+    ); // handle write half
+    let socket_rx = Reader::spawn(stream.rh, is_server, deflate, config, control_tx, shared, log); // handle read half
 
     (socket_rx, app_tx) // channel for communication with the upstream part
                         // of the library
 }
 
 // Writes bytes to the outbound tcp stream.
+// Messages come from the application (app_rx channel) or from the Reader
+// (control_rx channel) when it replies to the control frames.
 struct Writer<T> {
     stream_tx: stream::WriteHalf<T>,
-    mask_frames: bool,
+    frame_writer: FrameWriter,
     app_rx: Receiver<Msg>,
+    control_rx: Receiver<Msg>,
+    config: Config,
+    shared: Arc<Shared>,
+    last_write: Instant,
+    ping_no: u32,
+    log: Logger,
 }
 
 impl<T> Writer<T>
 where
     T: AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
 {
-    fn spawn(stream_tx: stream::WriteHalf<T>, mask_frames: bool, log: Logger) -> Sender<Msg> {
-        let (app_tx, app_rx): (Sender<Msg>, Receiver<Msg>) = mpsc::channel(1);
+    fn spawn(
+        stream_tx: stream::WriteHalf<T>,
+        mask_frames: bool,
+        deflate: Option<Deflate>,
+        config: Config,
+        shared: Arc<Shared>,
+        log: Logger,
+    ) -> (Sender<Msg>, Sender<Msg>) {
+        let (app_tx, app_rx): (Sender<Msg>, Receiver<Msg>) = mpsc::channel(config.buffer_size);
+        let (control_tx, control_rx): (Sender<Msg>, Receiver<Msg>) = mpsc::channel(1);
+
+        let mut frame_writer = FrameWriter::new(mask_frames, None);
+        frame_writer.deflater = deflate.map(|d| Deflater::with(d, config.deflate_level, config.deflate_strategy));
+        frame_writer.max_frame_size = config.max_frame_size;
+        frame_writer.stats = config.stats.clone();
+        frame_writer.random = config.random.clone();
 
         spawn(async move {
             let mut writer = Writer {
                 stream_tx,
-                mask_frames,
+                frame_writer,
                 app_rx,
+                control_rx,
+                config,
+                shared,
+                last_write: Instant::now(),
+                ping_no: 0,
+                log,
             };
 
-            if let Err(e) = writer.run().await {
-                error!(log, "{}", e);
+            let abort = writer.config.abort.clone();
+            tokio::select! {
+                biased;
+                // in-flight write is dropped, stream is closed without close
+                _ = abort.aborted() => trace!(writer.log, "writer aborted"),
+                res = writer.run() => if let Err(e) = res {
+                    error!(writer.log, "{}", e);
+                },
             }
-            trace!(log, "writer loop closed");
+            trace!(writer.log, "writer loop closed");
         });
 
-        app_tx
+        (app_tx, control_tx)
     }
 
     async fn run(&mut self) -> Result<(), Error> {
+        let mut keepalive = self.config.keepalive_interval.map(tokio::time::interval);
         loop {
-            let app = self.app_rx.recv().await;
-            match app {
-                Some(msg) => {
-                    let is_close = msg.is_close();
-                    self.write(msg).await?;
-                    if is_close {
+            let msg = tokio::select! {
+                biased;
+                _ = self.shared.write_closed.notified() => {
+                    // reader failed, stream is closed without the close frame
+                    self.stream_tx.shutdown().await.unwrap_or_default();
+                    break;
+                },
+                // closed control channel disables this branch
+                Some(msg) = self.control_rx.recv() => msg,
+                msg = self.config.outbox.pop() => msg,
+                app = self.app_rx.recv() => {
+                    let msg = match app {
+                        Some(msg) => msg,
+                        // when the application writer goes out of scope
+                        None => Msg::close(self.config.drop_close_status, String::new()),
+                    };
+                    if !self.config.outbox.is_empty() {
+                        // data messages queued before it are written first
+                        self.config.outbox.append(msg);
+                        continue;
+                    }
+                    msg
+                },
+                _ = tick(&mut keepalive) => {
+                    let interval = self.config.keepalive_interval.unwrap_or_default();
+                    if self.shared.since_last_read() >= interval * 2 {
+                        // peer is not responding, stop reader and writer
+                        error!(self.log, "keepalive timeout");
+                        self.shared.closed.notify_one();
                         break;
                     }
-                }
-                None => {
-                    // when the application writer goes out of scope
-                    self.write(Msg::Close(0)).await?;
+                    if self.last_write.elapsed() < interval {
+                        continue;
+                    }
+                    self.keepalive_ping()
+                },
+                _ = pong_overdue(&self.shared, self.config.pong_timeout) => {
+                    if self.shared.ping_sent_at().is_none() {
+                        continue; // pong arrived in the meantime
+                    }
+                    error!(self.log, "pong timeout");
+                    self.shared.fail(Timeout::Pong);
                     break;
+                },
+            };
+            let is_close = msg.is_close();
+            let written = if self.config.coalesce_writes && !is_close {
+                self.write_coalesced(msg).await
+            } else {
+                self.write(msg).await.map(|_| is_close)
+            };
+            let is_close = match written {
+                Ok(is_close) => is_close,
+                Err(e) => {
+                    if let Error::WriteTimeout = e {
+                        self.shared.fail(Timeout::Write);
+                    }
+                    return Err(e);
                 }
+            };
+            if is_close {
+                self.wait_close().await;
+                break;
             }
         }
         Ok(())
     }
 
+    // Waits for the Reader to receive close from the peer, then closes the
+    // stream. Reader is stopped if that doesn't happen in close_timeout.
+    async fn wait_close(&mut self) {
+        let shared = self.shared.clone();
+        if timeout(self.config.close_timeout, shared.read_closed.notified())
+            .await
+            .is_err()
+        {
+            warn!(self.log, "close timeout");
+            shared.closed.notify_one();
+        }
+        self.stream_tx.shutdown().await.unwrap_or_default();
+    }
+
+    // Keepalive pings are numbered when waiting for pongs, so the pong can
+    // be matched with the ping.
+    fn keepalive_ping(&mut self) -> Msg {
+        if self.config.pong_timeout.is_none() {
+            return Msg::Ping(Vec::new());
+        }
+        self.ping_no = self.ping_no.wrapping_add(1);
+        Msg::Ping(self.ping_no.to_be_bytes().to_vec())
+    }
+
     async fn write(&mut self, msg: Msg) -> Result<(), Error> {
-        let raw: Vec<u8> = msg.into_raw(self.mask_frames);
-        self.stream_tx.write(&raw).await?;
+        let mut raw = Vec::new();
+        let messages = self.encode(msg, &mut raw);
+        self.write_raw(&raw, messages).await
+    }
+
+    // Writes the message together with the messages already waiting in the
+    // application channel, up to COALESCE_SIZE bytes. Close ends the batch.
+    // Returns true if the close is written.
+    async fn write_coalesced(&mut self, msg: Msg) -> Result<bool, Error> {
+        let mut raw = Vec::new();
+        let mut messages = self.encode(msg, &mut raw);
+        let mut is_close = false;
+        while !is_close && raw.len() < COALESCE_SIZE {
+            // outbox first, application channel messages are appended to it
+            // while it is not empty
+            let next = match self.config.outbox.try_pop() {
+                Some(msg) => Ok(msg),
+                None => self.app_rx.try_recv(),
+            };
+            match next {
+                Ok(msg) => {
+                    is_close = msg.is_close();
+                    messages += self.encode(msg, &mut raw);
+                }
+                Err(_) => break, // channel drained, or closed for the next recv
+            }
+        }
+        self.write_raw(&raw, messages).await?;
+        Ok(is_close)
+    }
+
+    // Appends frames of the message to raw. Returns number of completed
+    // data messages.
+    fn encode(&mut self, msg: Msg, raw: &mut Vec<u8>) -> u64 {
+        if let (Msg::Ping(payload), Some(_)) = (&msg, self.config.pong_timeout) {
+            self.shared.ping_sent(payload);
+        }
+        if msg.is_close() {
+            // set before the write, peer's reply can't be read earlier
+            self.shared.close_sent.store(true, Ordering::SeqCst);
+        }
+        let msg = match msg {
+            Msg::Probe(payload, reply) => {
+                self.shared.probe_sent(&payload, reply);
+                Msg::Ping(payload)
+            }
+            msg => msg,
+        };
+        let message_end = matches!(
+            msg,
+            Msg::Text(_) | Msg::Binary(_) | Msg::Shared(_) | Msg::Fragment { fin: true, .. }
+        );
+        raw.extend(msg.into_raw(&mut self.frame_writer));
+        message_end as u64
+    }
+
+    async fn write_raw(&mut self, raw: &[u8], messages: u64) -> Result<(), Error> {
+        let write = self.stream_tx.write(raw);
+        match self.config.write_timeout {
+            Some(t) => timeout(t, write).await.map_err(|_| Error::WriteTimeout)??,
+            None => write.await?,
+        }
+        let frames = self.frame_writer.take_frames();
+        self.config.stats.written(frames, raw.len() as u64);
+        for _ in 0..messages {
+            self.config.stats.message_out();
+        }
+        self.last_write = Instant::now();
         Ok(())
     }
 }
 
+// Waits until the pong for the sent ping is overdue, forever if there is no
+// ping waiting for the pong.
+async fn pong_overdue(shared: &Shared, timeout: Option<Duration>) {
+    match (shared.ping_sent_at(), timeout) {
+        (Some(sent), Some(timeout)) => tokio::time::sleep_until((sent + timeout).into()).await,
+        _ => futures::future::pending().await,
+    }
+}
+
+// Peer closed the stream, or only its write side, in the middle of the read.
+fn is_eof(error: &Error) -> bool {
+    matches!(error, Error::IoError { error } if error.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+// Reads exactly buf.len() bytes. Errors with ReadTimeout if that is not
+// done before the deadline.
+async fn read_exact_until<T>(
+    stream_rx: &mut stream::ReadHalf<T>,
+    deadline: Option<tokio::time::Instant>,
+    buf: &mut [u8],
+) -> Result<(), Error>
+where
+    T: AsyncRead + std::marker::Unpin,
+{
+    let read = stream_rx.read_exact(buf);
+    match deadline {
+        Some(d) => tokio::time::timeout_at(d, read)
+            .await
+            .map_err(|_| Error::ReadTimeout)??,
+        None => read.await?,
+    };
+    Ok(())
+}
+
+// Reads exactly buf.len() bytes like read_exact_until. With the masking key
+// each chunk is unmasked as soon as it is read.
+async fn read_masked_until<T>(
+    stream_rx: &mut stream::ReadHalf<T>,
+    deadline: Option<tokio::time::Instant>,
+    buf: &mut [u8],
+    key: Option<[u8; 4]>,
+) -> Result<(), Error>
+where
+    T: AsyncRead + std::marker::Unpin,
+{
+    let read = read_masked(stream_rx, buf, key);
+    match deadline {
+        Some(d) => tokio::time::timeout_at(d, read)
+            .await
+            .map_err(|_| Error::ReadTimeout)??,
+        None => read.await?,
+    };
+    Ok(())
+}
+
+async fn read_masked<T>(stream_rx: &mut stream::ReadHalf<T>, buf: &mut [u8], key: Option<[u8; 4]>) -> io::Result<()>
+where
+    T: AsyncRead + std::marker::Unpin,
+{
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = stream_rx.read(&mut buf[pos..]).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(mut key) = key {
+            // chunk starts at pos, key is rotated to match its first byte
+            key.rotate_left(pos % 4);
+            mask(&mut buf[pos..pos + n], key);
+        }
+        pos += n;
+    }
+    Ok(())
+}
+
+// Waits for the next interval tick, forever if there is no interval.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
 // Reads bytes from the ReadHalf of the TcpStream.
 // Parses bytes as WebSocket frames, validates frame rules. Converts frames to
 // the Msg for communication with the application. Emits Msgs to the application
 // (tx channel), and in the case of control messages directly to the other side
 // of WebSocket (control_tx channel).
 struct Reader<T> {
-    deflate_supported: bool,
+    decoder: Decoder,
+    config: Config,
     stream_rx: stream::ReadHalf<T>,
     tx: Sender<Msg>,
+    control_tx: Sender<Msg>,
+    shared: Arc<Shared>,
     log: slog::Logger,
     header_buf: [u8; 14],
+    // until when the current frame must be read, with read_timeout
+    deadline: Option<tokio::time::Instant>,
+    // start of the current one second window and control frames in it
+    control_window: Instant,
+    control_count: u32,
 }
 
 impl<T> Reader<T>
 where
     T: AsyncRead + std::marker::Unpin + std::marker::Send + 'static,
 {
-    fn spawn(stream_rx: stream::ReadHalf<T>, deflate_supported: bool, log: slog::Logger) -> Receiver<Msg> {
-        let (tx, rx): (Sender<Msg>, Receiver<Msg>) = mpsc::channel(1);
+    fn spawn(
+        stream_rx: stream::ReadHalf<T>,
+        is_server: bool,
+        deflate: Option<Deflate>,
+        config: Config,
+        control_tx: Sender<Msg>,
+        shared: Arc<Shared>,
+        log: slog::Logger,
+    ) -> Receiver<Msg> {
+        let (tx, rx): (Sender<Msg>, Receiver<Msg>) = mpsc::channel(config.buffer_size);
+        let mut decoder = Decoder::new(is_server, deflate);
+        decoder.max_message_size = config.max_message_size;
+        decoder.max_fragments = config.max_fragments_per_message;
+        decoder.strict_utf8 = config.strict_utf8;
+        decoder.stats = config.stats.clone();
         let mut reader = Reader {
-            deflate_supported,
+            decoder,
+            config,
             stream_rx,
             tx, // output of the messages to the application
+            control_tx,
+            shared,
             log,
             header_buf: [0u8; 14],
+            deadline: None,
+            control_window: Instant::now(),
+            control_count: 0,
         };
 
         spawn(async move {
-            if let Err(e) = reader.read().await {
-                error!(reader.log, "{}", e);
+            let abort = reader.config.abort.clone();
+            tokio::select! {
+                biased;
+                _ = abort.aborted() => reader.aborted(),
+                res = reader.read() => if let Err(e) = res {
+                    error!(reader.log, "{}", e);
+                    reader.report_error(e);
+                },
             }
+            reader.shared.read_closed.notify_one();
         });
         return rx;
     }
 
+    // Application is not notified with close, channel to it is just dropped.
+    fn aborted(&self) {
+        trace!(self.log, "reader aborted");
+        self.config.stats.closed(STATUS_ABNORMAL_CLOSURE);
+        if let Some(events) = &self.config.events {
+            events.close(STATUS_ABNORMAL_CLOSURE, "");
+        }
+    }
+
+    fn report_error(&self, error: Error) {
+        if let Some(events) = &self.config.events {
+            events.error(error);
+        }
+    }
+
+    // Counts control frames in the one second window. False when the peer
+    // sends more than max_control_rate.
+    fn control_allowed(&mut self) -> bool {
+        let max = match self.config.max_control_rate {
+            Some(max) => max,
+            None => return true,
+        };
+        if self.control_window.elapsed() >= Duration::from_secs(1) {
+            self.control_window = Instant::now();
+            self.control_count = 0;
+        }
+        self.control_count = self.control_count.saturating_add(1);
+        self.control_count <= max
+    }
+
+    // Returns close status for the error.
+    fn failed(&self, error: Error) -> u16 {
+        match &error {
+            Error::InflateFailed(status) => error!(self.log, "inflate failed"; "status" => status),
+            _ => error!(self.log, "{}", error),
+        }
+        let status = codec::close_status(&error);
+        self.report_error(error);
+        status
+    }
+
+    // Payload is read into the buffer from the decoder and unmasked as the
+    // bytes arrive. The buffer is handed to the frame without a copy.
     async fn read_payload(&mut self, frame: &mut Frame) -> Result<(), Error> {
-        let l = frame.payload_len as usize;
-        if l > 0 {
-            let mut buf = vec![0u8; l];
-            self.stream_rx.read_exact(&mut buf).await?;
-            frame.set_payload(buf);
+        if frame.payload_len == 0 {
+            return Ok(());
         }
+        let mut buf = self.decoder.payload_buf(frame);
+        let key = if frame.mask { Some(frame.masking_key) } else { None };
+        read_masked_until(&mut self.stream_rx, self.deadline, &mut buf, key).await?;
+        frame.payload = buf;
         Ok(())
     }
 
     async fn read_header(&mut self) -> Result<Option<Frame>, Error> {
-        if let Err(e) = self.stream_rx.read_exact(&mut self.header_buf[0..2]).await {
+        // connection can be idle between frames, read timeout starts with
+        // the first byte of the frame
+        if let Err(e) = self.stream_rx.read_exact(&mut self.header_buf[0..1]).await {
             if e.kind() == io::ErrorKind::UnexpectedEof {
                 return Ok(None);
             }
             return Err(e.into());
         }
+        self.deadline = self.config.read_timeout.map(|t| tokio::time::Instant::now() + t);
+        match read_exact_until(&mut self.stream_rx, self.deadline, &mut self.header_buf[1..2]).await {
+            Err(e) if is_eof(&e) => return Ok(None),
+            other => other?,
+        }
         let mut frame = Frame::new(self.header_buf[0], self.header_buf[1]);
 
         if let Some(l) = frame.var_header_len() {
             let b = &mut self.header_buf[2..l + 2];
-            self.stream_rx.read_exact(b).await?;
+            match read_exact_until(&mut self.stream_rx, self.deadline, b).await {
+                Err(e) if is_eof(&e) => return Ok(None),
+                other => other?,
+            }
             frame.set_header(b);
         }
         Ok(Some(frame))
     }
 
     async fn read(&mut self) -> Result<(), Error> {
-        let mut fragment: Option<Frame> = None;
+        let mut reason = String::new();
+        let shared = self.shared.clone();
+        let app_tx = self.tx.clone();
         let status = loop {
             // read frame from tcp connection
-            let mut frame = match self.read_header().await? {
-                Some(f) => f,
-                None => {
-                    break 0;
-                }
+            let mut frame = tokio::select! {
+                header = self.read_header() => match header {
+                    Ok(Some(f)) => f,
+                    // connection dropped without the close frame
+                    Ok(None) => break STATUS_ABNORMAL_CLOSURE,
+                    Err(Error::ReadTimeout) => {
+                        error!(self.log, "read timeout");
+                        self.shared.fail(Timeout::Read);
+                        break STATUS_ABNORMAL_CLOSURE;
+                    }
+                    Err(e) => return Err(e),
+                },
+                // closed by the writer, without the close from the peer
+                _ = shared.closed.notified() => break STATUS_ABNORMAL_CLOSURE,
+                _ = app_tx.closed() => break 0, // application stopped receiving
             };
-            self.read_payload(&mut frame).await?;
-
-            // validate frame, if it is fragment wait for more
-            if let Err(e) = frame.validate(self.deflate_supported, fragment.is_some()) {
-                error!(self.log, "{}", e);
-                break STATUS_PROTOCOL_ERROR;
+            self.shared.touch();
+            // validate header before reading payload
+            if let Err(e) = self.decoder.header(&frame) {
+                break self.failed(e);
             }
-            if frame.is_fragment() {
-                trace!(self.log, "fragment" ;"opcode" =>  frame.opcode.desc(), "len" => frame.payload_len);
-                let (new_frame, new_fragment) = frame.into_fragment(fragment);
-                fragment = new_fragment;
-                match new_frame {
-                    Some(f) => frame = f,
-                    None => continue, // current frame is fragment, wait for more
+            // ping or pong flood, close has to be processed
+            if frame.opcode.control() && frame.opcode != Opcode::Close && !self.control_allowed() {
+                let max = self.config.max_control_rate.unwrap_or_default();
+                error!(self.log, "too many control frames"; "max" => max);
+                self.report_error(Error::ControlFrameFlood(max));
+                break STATUS_POLICY_VIOLATION;
+            }
+            if let Err(e) = self.read_payload(&mut frame).await {
+                if let Error::ReadTimeout = e {
+                    error!(self.log, "read timeout");
+                    self.shared.fail(Timeout::Read);
+                    break STATUS_ABNORMAL_CLOSURE;
                 }
+                if is_eof(&e) {
+                    // peer's write side is closed, our close can still be sent
+                    warn!(self.log, "eof in the frame payload");
+                    break STATUS_ABNORMAL_CLOSURE;
+                }
+                return Err(e);
             }
-            if let Err(e) = frame.validate_payload() {
-                error!(self.log, "{}", e);
-                break match e {
-                    Error::TextPayloadNotValidUTF8(_) => STATUS_NOT_VALID_UTF8,
-                    _ => STATUS_PROTOCOL_ERROR,
-                };
+            self.config.stats.frame_in(frame.header_len as u64 + frame.payload_len);
+
+            if frame.is_fragment() {
+                self.config.stats.fragment_in();
+                trace!(self.log, "fragment" ;"opcode" =>  frame.opcode.desc(), "len" => frame.payload_len);
             }
+            let frame = match self.decoder.message(frame) {
+                Ok(Some(f)) => f,
+                Ok(None) => continue, // current frame is fragment, wait for more
+                Err(e) => break self.failed(e),
+            };
 
             // process message
-            trace!(self.log, "read" ;"opcode" =>  frame.opcode.desc(), "payload_len" => frame.payload_len, "header_len" => frame.header_len, "mask" => frame.mask);
-            match frame.opcode.value() {
-                CLOSE => break frame.status(),
-                _ => self.tx.send(frame.into_ws_msg()).await?,
+            trace!(self.log, "read" ;"opcode" =>  frame.opcode.desc(), "payload_len" => frame.payload_len, "message_len" => frame.message_len(), "compressed" => frame.rsv1, "header_len" => frame.header_len, "mask" => frame.mask);
+            match frame.opcode {
+                Opcode::Ping => self.config.stats.ping(),
+                Opcode::Pong => {
+                    self.config.stats.pong();
+                    self.shared.pong_received(&frame.payload);
+                }
+                Opcode::Text | Opcode::Binary => self.config.stats.message_in(),
+                _ => (),
+            }
+            match frame.opcode {
+                Opcode::Close => {
+                    // status is validated with the payload
+                    let status = frame.close_status();
+                    if let Some(f) = self.decoder.take_fragment() {
+                        trace!(self.log, "fragment dropped on close"; "opcode" => f.opcode.desc(), "len" => f.payload_len);
+                    }
+                    reason = frame.text_payload;
+                    break status;
+                }
+                Opcode::Ping if self.config.auto_pong => {
+                    // writer could be already closed, nothing to reply then
+                    self.control_tx.send(Msg::Pong(frame.payload)).await.unwrap_or_default();
+                }
+                Opcode::Pong if !self.config.control_msgs => (), // hidden from the application
+                _ => {
+                    if self.tx.send(Msg::from(frame)).await.is_err() {
+                        break 0; // application stopped receiving
+                    }
+                }
             }
         };
-        self.tx.send(Msg::Close(status)).await.unwrap_or_default();
+        self.config.stats.closed(status);
+        let failure = self.shared.failure.lock().unwrap().take();
+        if let Some(events) = &self.config.events {
+            match failure {
+                Some(failure) => events.error(failure.into()),
+                None => events.close(status, &reason),
+            }
+        }
+        if let Some(failure) = failure {
+            // connection is already abandoned by the writer
+            self.tx.send(Msg::Failed(failure)).await.unwrap_or_default();
+        } else {
+            if !self.shared.close_sent() {
+                // echo close to the peer, closing handshake doesn't wait for
+                // the application
+                let echo = Msg::close(status, reason.clone());
+                self.control_tx.send(echo).await.unwrap_or_default();
+            }
+            self.tx.send(Msg::close(status, reason)).await.unwrap_or_default();
+        }
         trace!(self.log, "reader loop closed");
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct Frame {
-    fin: bool,
-    rsv1: bool,
-    rsv: u8,
-    mask: bool,
-    opcode: Opcode,
-    payload_len: u64,
-    header_len: u8,
-    masking_key: [u8; 4],
-    payload: Vec<u8>,
-    text_payload: String,
+impl From<Frame> for Msg {
+    fn from(frame: Frame) -> Self {
+        match frame.opcode {
+            Opcode::Text => Msg::Text(frame.text_payload),
+            Opcode::Binary => Msg::Binary(frame.payload),
+            Opcode::Ping => Msg::Ping(frame.payload),
+            Opcode::Pong => Msg::Pong(frame.payload),
+            Opcode::Close => Msg::close(frame.close_status(), frame.text_payload),
+            Opcode::Continuation | Opcode::Reserved(_) => Msg::Close(None),
+        }
+    }
 }
 
-const STATUS_PROTOCOL_ERROR: u16 = 1002;
-const STATUS_NOT_VALID_UTF8: u16 = 1007;
+// Coalesced writes are flushed when they reach this size.
+const COALESCE_SIZE: usize = 64 * 1024;
+const STATUS_POLICY_VIOLATION: u16 = 1008;
+// Reported locally when the connection is lost without the close frame, never
+// sent to the peer.
+const STATUS_ABNORMAL_CLOSURE: u16 = 1006;
 
-// data frame types
-const CONTINUATION: u8 = 0;
-const TEXT: u8 = 1;
-const BINARY: u8 = 2;
-// control frame types
-const CLOSE: u8 = 8;
-const PING: u8 = 9;
-const PONG: u8 = 10;
-
-#[derive(Debug)]
-struct Opcode(u8);
+#[cfg(test)]
+mod tests {
+    use super::super::codec::{Inflater, STATUS_MESSAGE_TOO_BIG, STATUS_NOT_VALID_UTF8, STATUS_PROTOCOL_ERROR};
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+    use miniz_oxide::inflate::decompress_to_vec;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-impl Opcode {
-    fn new(opcode: u8) -> Self {
-        Self(opcode)
-    }
-    fn value(&self) -> u8 {
-        self.0
-    }
-    fn valid(&self) -> bool {
-        self.data() || self.control() || self.continuation()
-    }
-    fn data(&self) -> bool {
-        self.0 == TEXT || self.0 == BINARY
+    fn text_frame(text: &str) -> Vec<u8> {
+        FrameWriter::new(false, None).text(text.to_owned())
     }
-    fn control(&self) -> bool {
-        self.0 == CLOSE || self.0 == PING || self.0 == PONG
+
+    // compresses single message without context takeover
+    fn deflate(payload: &[u8]) -> Vec<u8> {
+        Deflater::new(Deflate {
+            deflate_no_context_takeover: true,
+            ..Deflate::default()
+        })
+        .deflate(payload)
+        .unwrap()
     }
-    fn continuation(&self) -> bool {
-        self.0 == CONTINUATION
+
+    #[test]
+    fn test_text_message() {
+        let buf = text_frame("abc");
+        assert_eq!(5, buf.len());
+        assert_eq!([0x81, 0x03, 0x61, 0x62, 0x63], buf[0..]);
+
+        let buf = text_frame(
+            "The length of the Payload data, in bytes: if 0-125, that is the payload length.  
+        If 126, the following 2 bytes interpreted as a 16-bit unsigned integer are the payload length.",
+        );
+        assert_eq!(188, buf.len());
+        assert_eq!([0x81, 0x7e, 0x00, 184], buf[0..4]);
+        assert_eq!(184, buf[3]); // 175 is body length
+
+        //println!("{:02x?}", buf);
     }
-    fn text(&self) -> bool {
-        self.0 == TEXT
+
+    #[test]
+    fn masking_key_source() {
+        let mut fw = FrameWriter::new(true, None);
+        fw.random = Random::new(|buf| buf.copy_from_slice(&[1, 2, 3, 4]));
+        let buf = fw.text("abc".to_owned());
+        assert_eq!([0x81, 0x83, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2, b'c' ^ 3], buf[..]);
     }
-    #[allow(dead_code)]
-    fn binary(&self) -> bool {
-        self.0 == BINARY
+    #[test]
+    fn test_compress_decompress() {
+        let data = "hello world";
+        let compressed = compress_to_vec(data.as_bytes(), 6);
+        let decompressed = decompress_to_vec(compressed.as_slice()).expect("Failed to decompress!");
+        assert_eq!(data, str::from_utf8(&decompressed).unwrap());
     }
-    #[allow(dead_code)]
-    fn close(&self) -> bool {
-        self.0 == CLOSE
+    #[test]
+    fn frame_inflate() {
+        let mut inflater = Inflater::new(Deflate::default());
+        let payload = inflater
+            .inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024)
+            .unwrap();
+        assert_eq!(b"Hello", &payload[..]);
+        assert!(inflater.inflate(&[0xff, 0xff], 1024).is_err());
     }
-    fn desc(&self) -> &str {
-        match self.0 {
-            CONTINUATION => "continuation",
-            TEXT => "text",
-            BINARY => "binary",
-            CLOSE => "close",
-            PING => "ping",
-            PONG => "pong",
-            _ => "reserved",
+
+    #[test]
+    fn inflate_max_size() {
+        let payload = deflate(&vec![0u8; 100_000]);
+        assert!(payload.len() < 1000);
+        let mut inflater = Inflater::new(Deflate::default());
+        match inflater.inflate(&payload, 99_999) {
+            Err(Error::MessageTooBig(99_999)) => (),
+            other => panic!("expected message too big got {:?}", other),
         }
+        let mut inflater = Inflater::new(Deflate::default());
+        assert_eq!(100_000, inflater.inflate(&payload, 100_000).unwrap().len());
     }
-}
 
-impl fmt::Display for Opcode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.desc())
-    }
-}
+    #[test]
+    fn inflate_context_takeover() {
+        // compressor keeps sliding window between messages
+        let mut deflater = Deflater::new(Deflate::default());
+        let text = "The length of the Payload data, in bytes: if 0-125, that is the payload length.";
+        let first = deflater.deflate(text.as_bytes()).unwrap();
+        let second = deflater.deflate(text.as_bytes()).unwrap();
+        assert!(second.len() < first.len() / 2); // second references the first
 
-enum Fragment {
-    Start,
-    Middle,
-    End,
-    None,
-}
+        let mut inflater = Inflater::new(Deflate::default());
+        assert_eq!(text.as_bytes(), &inflater.inflate(&first, 1024).unwrap()[..]);
+        assert_eq!(text.as_bytes(), &inflater.inflate(&second, 1024).unwrap()[..]);
+
+        // without context takeover each message is decompressed on its own
+        let mut inflater = Inflater::new(Deflate {
+            inflate_no_context_takeover: true,
+            ..Deflate::default()
+        });
+        let msg = deflate(text.as_bytes());
+        assert_eq!(msg, deflate(text.as_bytes()));
+        assert_eq!(text.as_bytes(), &inflater.inflate(&msg, 1024).unwrap()[..]);
+        assert_eq!(text.as_bytes(), &inflater.inflate(&msg, 1024).unwrap()[..]);
+    }
+
+    #[test]
+    fn deflate_window_bits() {
+        // with the smaller window only matches at distance 1 are used
+        let text = "abcdefgh".repeat(100);
+        let mut deflater = Deflater::new(Deflate {
+            deflate_window_bits: 9,
+            ..Deflate::default()
+        });
+        let small_window = deflater.deflate(text.as_bytes()).unwrap();
+        assert!(small_window.len() > deflate(text.as_bytes()).len());
+        let mut inflater = Inflater::new(Deflate::default());
+        assert_eq!(text.as_bytes(), &inflater.inflate(&small_window, 1024).unwrap()[..]);
 
-impl Frame {
-    fn new(byte1: u8, byte2: u8) -> Frame {
-        let opcode = byte1 & 0b0000_1111u8;
-        Frame {
-            fin: byte1 & 0b1000_0000u8 != 0,
-            rsv1: byte1 & 0b0100_0000u8 != 0,
-            rsv: (byte1 & 0b0111_0000u8) >> 4,
-            opcode: Opcode::new(opcode),
-            mask: byte2 & 0b1000_0000u8 != 0,
-            payload_len: (byte2 & 0b0111_1111u8) as u64,
-            header_len: 2,
-            masking_key: [0; 4],
-            payload: vec![0; 0],
-            text_payload: String::new(),
+        let text = "a".repeat(1000);
+        let mut deflater = Deflater::new(Deflate {
+            deflate_window_bits: 8,
+            ..Deflate::default()
+        });
+        assert!(deflater.deflate(text.as_bytes()).unwrap().len() < 100);
+    }
+
+    #[test]
+    fn deflate_level_and_strategy() {
+        let text = "abcdefgh".repeat(100);
+        let deflated = |level: u8, strategy: DeflateStrategy| {
+            let msg = Deflater::with(Deflate::default(), level, strategy)
+                .deflate(text.as_bytes())
+                .unwrap();
+            let mut inflater = Inflater::new(Deflate::default());
+            assert_eq!(text.as_bytes(), &inflater.inflate(&msg, 1024).unwrap()[..]);
+            msg.len()
+        };
+        let default = deflated(DEFLATE_LEVEL, DeflateStrategy::Default);
+        assert_eq!(deflate(text.as_bytes()).len(), default);
+        assert_eq!(default, deflated(DEFLATE_LEVEL, DeflateStrategy::Filtered));
+        // no matches at distance 1 or none at all
+        assert!(deflated(DEFLATE_LEVEL, DeflateStrategy::Rle) > default);
+        assert!(deflated(DEFLATE_LEVEL, DeflateStrategy::HuffmanOnly) > default);
+        // level 0 stores the payload
+        assert!(deflated(0, DeflateStrategy::Default) > text.len());
+    }
+
+    // parses single unmasked frame produced by FrameWriter
+    fn parse_frame(buf: &[u8]) -> Frame {
+        let mut f = Frame::new(buf[0], buf[1]);
+        let start = match f.var_header_len() {
+            Some(l) => {
+                f.set_header(&buf[2..l + 2]);
+                l + 2
+            }
+            None => 2,
+        };
+        f.set_payload(buf[start..].to_vec());
+        f
+    }
+
+    // Feeds raw bytes to the Reader, returns all messages Reader emits until
+    // the stream is closed.
+    async fn read_msgs(raw: Vec<u8>, is_server: bool, config: Config) -> Vec<Msg> {
+        read_deflate_msgs(raw, is_server, None, config).await
+    }
+
+    async fn read_deflate_msgs(raw: Vec<u8>, is_server: bool, deflate: Option<Deflate>, config: Config) -> Vec<Msg> {
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let stream = Stream::new(local);
+        let (control_tx, _) = mpsc::channel(1);
+        let shared = Arc::new(Shared::new());
+        let mut rx = Reader::spawn(
+            stream.rh,
+            is_server,
+            deflate,
+            config,
+            control_tx,
+            shared,
+            crate::log::null(),
+        );
+        spawn(async move {
+            peer.write_all(&raw).await.unwrap_or_default();
+        });
+        let mut msgs = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            msgs.push(msg);
         }
+        msgs
     }
 
-    // length of the rest of the header after first two bytes
-    fn var_header_len(&mut self) -> Option<usize> {
-        if !self.mask && self.payload_len < 126 {
-            return None;
+    #[tokio::test]
+    async fn read_masked_payloads() {
+        // payloads larger than the stream buffers are read and unmasked in
+        // chunks which don't start at the multiple of 4
+        let mut fw = FrameWriter::new(true, None);
+        let payload = |size: usize| (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let sizes = [100, 4, 4096, 4097, 3];
+        let mut raw = Vec::new();
+        for size in &sizes {
+            raw.extend(fw.binary(payload(*size)));
         }
-        let mut n: usize = if self.mask { 4 } else { 0 };
-        if self.payload_len >= 126 {
-            n += 2;
-            if self.payload_len == 127 {
-                n += 6;
+        // continuations are read into the reused buffer, larger then smaller
+        raw.extend(fw.frame(false, Opcode::Binary, false, &payload(10)));
+        raw.extend(fw.frame(false, Opcode::Continuation, false, &payload(3000)));
+        raw.extend(fw.frame(true, Opcode::Continuation, false, &payload(5)));
+        let msgs = read_msgs(raw, true, Config::default()).await;
+        assert_eq!(sizes.len() + 2, msgs.len());
+        for (i, size) in sizes.iter().enumerate() {
+            match &msgs[i] {
+                Msg::Binary(data) => assert_eq!(&payload(*size), data),
+                other => panic!("expected binary got {:?}", other),
             }
         }
-        self.header_len = n as u8 + 2;
-        Some(n)
+        let mut expected = payload(10);
+        expected.extend(payload(3000));
+        expected.extend(payload(5));
+        match &msgs[sizes.len()] {
+            Msg::Binary(data) => assert_eq!(&expected, data),
+            other => panic!("expected binary got {:?}", other),
+        }
     }
 
-    fn set_header(&mut self, buf: &[u8]) {
-        let mask_start = if self.mask { buf.len() - 4 } else { buf.len() };
-        if mask_start == 8 {
-            let bytes: [u8; 8] = [buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]];
-            self.payload_len = u64::from_be_bytes(bytes);
+    // byte at a time masking, reference for the mask
+    fn mask_bytes(payload: &mut [u8], key: [u8; 4]) {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= key[i % 4];
         }
-        if mask_start == 2 {
-            let bytes: [u8; 2] = [buf[0], buf[1]];
-            self.payload_len = u16::from_be_bytes(bytes) as u64;
+    }
+
+    #[test]
+    fn mask_words() {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        for len in 0..40 {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let mut expected = data.clone();
+            mask_bytes(&mut expected, key);
+            let mut masked = data.clone();
+            mask(&mut masked, key);
+            assert_eq!(expected, masked);
+            mask(&mut masked, key);
+            assert_eq!(data, masked);
+        }
+    }
+
+    // Compares word and byte at a time masking of the 16 MiB payload.
+    // Run with: cargo test --release mask_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn mask_throughput() {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut payload = vec![0xa5u8; 16 * 1024 * 1024];
+        let bench = |name: &str, f: &dyn Fn(&mut Vec<u8>, [u8; 4]), payload: &mut Vec<u8>| {
+            let start = Instant::now();
+            for _ in 0..10 {
+                f(payload, key);
+            }
+            let elapsed = start.elapsed();
+            println!("{}: {:.0} MiB/s", name, 160.0 / elapsed.as_secs_f64());
+        };
+        bench("bytes", &|p, k| mask_bytes(p, k), &mut payload);
+        bench("words", &|p, k| mask(p, k), &mut payload);
+        assert_eq!(vec![0xa5u8; 16 * 1024 * 1024], payload); // even number of passes
+    }
+
+    #[test]
+    fn shared_payload() {
+        let payload = vec![0xa5u8; 300];
+        let shared: Arc<[u8]> = Arc::from(payload.clone());
+        for deflate in &[None, Some(Deflate::default())] {
+            let mut fw = FrameWriter::new(false, *deflate);
+            let binary = Msg::Binary(payload.clone()).into_raw(&mut fw);
+            let mut fw = FrameWriter::new(false, *deflate);
+            assert_eq!(binary, Msg::Shared(shared.clone()).into_raw(&mut fw));
         }
-        if self.mask {
-            for i in 0..4 {
-                self.masking_key[i] = buf[mask_start + i];
+        // masked frame doesn't touch the shared payload
+        let mut fw = FrameWriter::new(true, None);
+        let raw = Msg::Shared(shared.clone()).into_raw(&mut fw);
+        assert_eq!(4 + 4 + 300, raw.len());
+        assert_eq!(payload[..], shared[..]);
+    }
+
+    // Broadcast of the large binary message to many sockets, payload cloned
+    // for each socket vs shared.
+    // Run with: cargo test --release broadcast_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn broadcast_throughput() {
+        const SOCKETS: usize = 1000;
+        let payload = vec![0xa5u8; 1024 * 1024];
+        let mut fw = FrameWriter::new(false, None);
+        let bench = |name: &str, f: &mut dyn FnMut() -> Vec<u8>| {
+            let start = Instant::now();
+            for _ in 0..SOCKETS {
+                assert_eq!(payload.len() + 10, f().len());
             }
+            let elapsed = start.elapsed();
+            println!("{}: {:.0} messages/s", name, SOCKETS as f64 / elapsed.as_secs_f64());
+        };
+        bench("cloned", &mut || Msg::Binary(payload.clone()).into_raw(&mut fw));
+        let shared: Arc<[u8]> = Arc::from(payload.clone());
+        let mut fw = FrameWriter::new(false, None);
+        bench("shared", &mut || Msg::Shared(shared.clone()).into_raw(&mut fw));
+    }
+
+    // Throughput of the Reader for a flood of small masked frames.
+    // Run with: cargo test --release read_throughput -- --ignored --nocapture
+    #[tokio::test]
+    #[ignore]
+    async fn read_throughput() {
+        const FRAMES: usize = 1_000_000;
+        let mut fw = FrameWriter::new(true, None);
+        let mut raw = Vec::with_capacity(FRAMES * 10);
+        for _ in 0..FRAMES {
+            raw.extend(fw.text("abcd".to_owned()));
         }
+        let (mut peer, local) = tokio::io::duplex(64 * 1024);
+        let stream = Stream::new(local);
+        let (control_tx, _) = mpsc::channel(1);
+        let shared = Arc::new(Shared::new());
+        let mut rx = Reader::spawn(
+            stream.rh,
+            true,
+            None,
+            Config::default(),
+            control_tx,
+            shared,
+            crate::log::null(),
+        );
+        let start = Instant::now();
+        spawn(async move {
+            peer.write_all(&raw).await.unwrap_or_default();
+        });
+        let mut n = 0;
+        while let Some(Msg::Text(_)) = rx.recv().await {
+            n += 1;
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(FRAMES, n);
+        println!(
+            "{} frames in {:?}, {:.0} frames/s",
+            n,
+            elapsed,
+            n as f64 / elapsed.as_secs_f64()
+        );
     }
 
-    fn set_payload(&mut self, mut payload: Vec<u8>) {
-        if self.mask {
-            mask(&mut payload, self.masking_key);
+    // Counts reads from the underlying stream.
+    struct CountingReader<R> {
+        inner: R,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_read(cx, buf)
         }
-        self.payload = payload;
     }
 
-    fn is_rsv_ok(&self, deflate_supported: bool) -> bool {
-        if deflate_supported {
-            return self.rsv == 0 || self.rsv == 4;
+    #[tokio::test]
+    async fn buffered_reads() {
+        // many small frames are served from one read of the stream
+        const FRAMES: usize = 10_000;
+        let mut fw = FrameWriter::new(true, None);
+        let mut raw = Vec::with_capacity(FRAMES * 10);
+        for _ in 0..FRAMES {
+            raw.extend(fw.text("abcd".to_owned()));
         }
-        // rsv must be 0, when no extension defining RSV meaning has been negotiated
-        self.rsv == 0
+        let (mut peer, local) = tokio::io::duplex(64 * 1024);
+        let reads = Arc::new(AtomicUsize::new(0));
+        let rh = stream::ReadHalf::new(CountingReader {
+            inner: local,
+            reads: reads.clone(),
+        });
+        let (control_tx, _) = mpsc::channel(1);
+        let shared = Arc::new(Shared::new());
+        let config = Config::default();
+        let mut rx = Reader::spawn(rh, true, None, config, control_tx, shared, crate::log::null());
+        spawn(async move {
+            peer.write_all(&raw).await.unwrap_or_default();
+        });
+        let mut n = 0;
+        while let Some(Msg::Text(_)) = rx.recv().await {
+            n += 1;
+        }
+        assert_eq!(FRAMES, n);
+        assert!(reads.load(Ordering::Relaxed) < FRAMES / 10);
     }
 
-    fn validate(&self, deflate_supported: bool, in_continuation: bool) -> Result<(), Error> {
-        if !self.opcode.valid() {
-            return Err(Error::WrongHeader(format!("reserved opcode {}", self.opcode.value())));
+    fn close_status(msgs: &[Msg]) -> u16 {
+        match msgs.last() {
+            Some(msg @ Msg::Close(_)) => msg.close_status(),
+            other => panic!("expected close got {:?}", other),
         }
-        if self.opcode.control() {
-            // control frames must be short, payload <= 125 bytes
-            // can't be split into fragments
-            if self.payload_len > 125 {
-                return Err(Error::WrongHeader(format!(
-                    "too long control frame {} > 125",
-                    self.payload_len
-                )));
+    }
+
+    #[tokio::test]
+    async fn reader_max_message_size() {
+        // 64 bit length frame header announcing 1 TiB payload
+        let mut raw = vec![0x82, 0x7f];
+        raw.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&msgs));
+
+        let config = Config {
+            max_message_size: 10,
+            ..Config::default()
+        };
+        // single frame within the limit
+        let msgs = read_msgs(text_frame("0123456789"), false, config.clone()).await;
+        assert_eq!(2, msgs.len());
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&msgs));
+
+        // fragments over the limit
+        let mut raw = vec![0x01, 0x06];
+        raw.extend_from_slice(b"012345");
+        raw.extend_from_slice(&[0x80, 0x06]);
+        raw.extend_from_slice(b"678901");
+        let msgs = read_msgs(raw, false, config).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&msgs));
+    }
+
+    #[test]
+    fn into_fragment_without_start() {
+        let fw = FrameWriter::new(false, None);
+        // continuation as the first frame
+        for fin in &[false, true] {
+            let frame = parse_frame(&fw.frame(*fin, Opcode::Continuation, false, b"abc"));
+            match frame.into_fragment(None, &mut Vec::new()) {
+                Err(Error::WrongHeader(_)) => (),
+                _ => panic!("expected wrong header"),
             }
-            if !self.fin {
-                return Err(Error::WrongHeader("fragmented control frame".to_owned()));
+        }
+        // started fragment is completed by the continuation
+        let first = parse_frame(&fw.frame(false, Opcode::Text, false, b"ab"));
+        let (frame, fragment) = first.into_fragment(None, &mut Vec::new()).unwrap();
+        assert!(frame.is_none());
+        let last = parse_frame(&fw.frame(true, Opcode::Continuation, false, b"c"));
+        let mut spare = Vec::new();
+        let (frame, fragment) = last.into_fragment(fragment, &mut spare).unwrap();
+        assert!(fragment.is_none());
+        assert_eq!(b"abc".to_vec(), frame.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn max_fragments_per_message() {
+        let config = Config {
+            max_fragments_per_message: Some(4),
+            ..Config::default()
+        };
+        let fw = FrameWriter::new(false, None);
+        let fragments = |n: usize| {
+            let mut raw = fw.frame(false, Opcode::Text, false, &[]);
+            for _ in 2..n {
+                raw.extend(fw.frame(false, Opcode::Continuation, false, &[]));
             }
-        } else {
-            // continuation (waiting for more fragments) frames must be in order
-            // start/middle.../end
-            if !in_continuation && self.opcode.continuation() {
-                return Err(Error::WrongHeader("not in continuation".to_owned()));
+            raw.extend(fw.frame(true, Opcode::Continuation, false, &[]));
+            raw
+        };
+        // within the limit
+        let msgs = read_msgs(fragments(4), false, config.clone()).await;
+        assert_eq!(2, msgs.len());
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&msgs));
+
+        // one empty fragment over the limit
+        let msgs = read_msgs(fragments(5), false, config).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&msgs));
+
+        // unlimited by default
+        let msgs = read_msgs(fragments(1000), false, Config::default()).await;
+        assert_eq!(2, msgs.len());
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn server_rejects_unmasked_frame() {
+        let msgs = read_msgs(text_frame("abc"), true, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+
+        let raw = FrameWriter::new(true, None).text("abc".to_owned());
+        let msgs = read_msgs(raw, true, Config::default()).await;
+        assert_eq!(2, msgs.len());
+        match &msgs[0] {
+            Msg::Text(text) => assert_eq!("abc", text),
+            other => panic!("expected text got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_reason() {
+        let raw = FrameWriter::new(false, None).close(Some((1001, "going away".to_owned())));
+        assert_eq!([0x88, 12, 0x03, 0xe9], raw[0..4]);
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        match &msgs[..] {
+            [Msg::Close(Some((status, reason)))] => {
+                assert_eq!(1001, *status);
+                assert_eq!("going away", reason);
             }
-            if in_continuation && !self.opcode.continuation() {
-                return Err(Error::WrongHeader("fin frame during continuation".to_owned()));
+            other => panic!("expected close got {:?}", other),
+        }
+
+        // reason must be valid utf-8
+        let raw = vec![0x88, 0x04, 0x03, 0xe9, 0xc3, 0x28];
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(STATUS_NOT_VALID_UTF8, close_status(&msgs));
+
+        // reason is truncated to fit into control frame
+        let raw = FrameWriter::new(false, None).close(Some((1000, "a".repeat(200))));
+        assert_eq!(127, raw.len());
+    }
+
+    #[tokio::test]
+    async fn close_payload() {
+        let close = |close: Option<(u16, &str)>| {
+            let close = close.map(|(status, reason)| (status, reason.to_owned()));
+            Msg::Close(close).into_raw(&mut FrameWriter::new(false, None))
+        };
+        assert_eq!([0x88, 0x00], close(None)[..]);
+        assert_eq!([0x88, 0x02, 0x03, 0xe8], close(Some((1000, "")))[..]);
+        let raw = close(Some((1000, "bye")));
+        assert_eq!([0x88, 0x05, 0x03, 0xe8, b'b', b'y', b'e'], raw[..]);
+        // status 0 is the close without payload
+        assert!(matches!(Msg::close(0, "bye".to_owned()), Msg::Close(None)));
+
+        // received close keeps the form
+        let expected = [None, Some((1000, "")), Some((1000, "bye"))];
+        for expected in &expected {
+            match &read_msgs(close(*expected), false, Config::default()).await[..] {
+                [Msg::Close(close)] => assert_eq!(*expected, close.as_ref().map(|(s, r)| (*s, r.as_str()))),
+                other => panic!("expected close got {:?}", other),
             }
         }
-        if !self.is_rsv_ok(deflate_supported) {
-            // only bit 1 of rsv is currently used
-            return Err(Error::WrongHeader("wrong rsv".to_owned()));
+    }
+
+    #[test]
+    fn close_status_codes() {
+        let status = |code: u16| {
+            let close = Some((code, String::new())).filter(|_| code != 0);
+            parse_frame(&FrameWriter::new(false, None).close(close)).status()
+        };
+        for code in &[0, 1000, 1001, 1003, 1007, 1011, 3000, 4000, 4999] {
+            assert_eq!(*code, status(*code).unwrap());
+        }
+        for code in &[1, 999, 1004, 1005, 1006, 1012, 1015, 1016, 2000, 2999, 5000, 65535] {
+            assert!(status(*code).is_err(), "code {}", code);
         }
-        Ok(())
     }
 
-    fn validate_payload(&mut self) -> Result<(), Error> {
-        self.inflate()?;
-        if !self.opcode.text() {
-            return Ok(());
+    #[tokio::test]
+    async fn strict_utf8() {
+        let raw = vec![0x81, 0x04, b'a', 0xc3, 0x28, b'b'];
+        let msgs = read_msgs(raw.clone(), false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_NOT_VALID_UTF8, close_status(&msgs));
+
+        let config = Config {
+            strict_utf8: false,
+            ..Config::default()
+        };
+        let msgs = read_msgs(raw, false, config).await;
+        match &msgs[0] {
+            Msg::Text(text) => assert_eq!("a\u{fffd}(b", text),
+            other => panic!("expected text got {:?}", other),
         }
-        self.text_payload = str::from_utf8(&self.payload)?.to_owned();
-        Ok(())
     }
 
-    fn inflate(&mut self) -> Result<(), Error> {
-        if self.rsv1 && self.payload_len > 0 {
-            match inflate_bytes(&self.payload) {
-                Ok(p) => self.payload = p,
-                Err(e) => return Err(Error::InflateFailed(e)),
+    #[tokio::test]
+    async fn close_payload_len() {
+        // empty payload is a close without status
+        let msgs = read_msgs(vec![0x88, 0x00], false, Config::default()).await;
+        assert_eq!(0, close_status(&msgs));
+
+        // 1 byte can't hold the status code
+        let msgs = read_msgs(vec![0x88, 0x01, 0x03], false, Config::default()).await;
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+
+        let msgs = read_msgs(vec![0x88, 0x02, 0x03, 0xe8], false, Config::default()).await;
+        assert_eq!(1000, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn close_echo() {
+        for initiator_is_server in &[true, false] {
+            let (a, b) = tokio::io::duplex(1024);
+            let is_server = *initiator_is_server;
+            let (mut initiator_rx, initiator_tx) =
+                start(Stream::new(a), is_server, None, Config::default(), crate::log::null()).await;
+            let (mut peer_rx, _peer_tx) =
+                start(Stream::new(b), !is_server, None, Config::default(), crate::log::null()).await;
+
+            initiator_tx.send(Msg::close(1000, "bye".to_owned())).await.unwrap();
+            // peer's reader echoes close, application is not involved
+            match initiator_rx.recv().await {
+                Some(Msg::Close(Some((status, reason)))) => {
+                    assert_eq!(1000, status);
+                    assert_eq!("bye", reason);
+                }
+                other => panic!("expected close got {:?}", other),
+            }
+            assert!(initiator_rx.recv().await.is_none());
+            match peer_rx.recv().await {
+                Some(Msg::Close(Some((status, reason)))) => {
+                    assert_eq!(1000, status);
+                    assert_eq!("bye", reason);
+                }
+                other => panic!("expected close got {:?}", other),
             }
+            assert!(peer_rx.recv().await.is_none());
         }
-        Ok(())
     }
 
-    fn fragment(&self) -> Fragment {
-        if !self.fin && self.opcode.data() {
-            return Fragment::Start;
+    #[tokio::test]
+    async fn close_with_invalid_status() {
+        let raw = FrameWriter::new(false, None).close(Some((1005, String::new())));
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn auto_pong() {
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        let ping = FrameWriter::new(true, None).ping(b"abc".to_vec());
+        peer.write_all(&ping).await.unwrap();
+        let mut pong = [0u8; 5];
+        peer.read_exact(&mut pong).await.unwrap();
+        assert_eq!([0x8a, 0x03, b'a', b'b', b'c'], pong);
+        drop(peer);
+        // ping is not forwarded to the application
+        assert!(rx.recv().await.unwrap().is_close());
+
+        let config = Config {
+            auto_pong: false,
+            control_msgs: true,
+            ..Config::default()
+        };
+        let raw = FrameWriter::new(false, None).ping(b"abc".to_vec());
+        let msgs = read_msgs(raw, false, config).await;
+        assert_eq!("ping", msgs[0].kind());
+    }
+
+    #[tokio::test]
+    async fn control_between_fragments() {
+        let fw = FrameWriter::new(true, None);
+        let fragments = |control: Vec<u8>| {
+            let mut raw = fw.frame(false, Opcode::Text, false, b"ab");
+            raw.extend(control);
+            raw.extend(fw.frame(true, Opcode::Continuation, false, b"c"));
+            raw
+        };
+
+        // ping is answered, message is reassembled
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        peer.write_all(&fragments(fw.ping(b"p".to_vec()))).await.unwrap();
+        let mut pong = [0u8; 3];
+        peer.read_exact(&mut pong).await.unwrap();
+        assert_eq!([0x8a, 0x01, b'p'], pong);
+        match rx.recv().await {
+            Some(Msg::Text(text)) => assert_eq!("abc", text),
+            other => panic!("expected text got {:?}", other),
         }
-        if !self.fin && self.opcode.continuation() {
-            return Fragment::Middle;
+
+        // ping is passed to the application before the message
+        let config = Config {
+            auto_pong: false,
+            control_msgs: true,
+            ..Config::default()
+        };
+        let msgs = read_msgs(fragments(fw.ping(b"p".to_vec())), true, config).await;
+        let kinds: Vec<&str> = msgs.iter().map(|m| m.kind()).collect();
+        assert_eq!(vec!["ping", "text", "close"], kinds);
+
+        // close in the middle drops the pending fragment
+        let close_frame = fw.close(Some((1000, String::new())));
+        let msgs = read_msgs(fragments(close_frame.clone()), true, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(1000, close_status(&msgs));
+
+        // close is echoed, continuation after it is not read
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        peer.write_all(&fragments(close_frame)).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Close(Some((status, _)))) => assert_eq!(1000, status),
+            other => panic!("expected close got {:?}", other),
+        }
+        assert!(rx.recv().await.is_none());
+        let mut close = Vec::new();
+        peer.read_to_end(&mut close).await.unwrap();
+        assert_eq!([0x88, 0x02, 0x03, 0xe8], close[..]);
+
+        // data frame can't interrupt the fragmented message
+        let text = FrameWriter::new(true, None).text("x".to_owned());
+        let msgs = read_msgs(fragments(text), true, Config::default()).await;
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events() {
+        let events = |raw: Vec<u8>| async move {
+            let (tx, mut rx) = mpsc::channel(8);
+            let config = Config {
+                events: Some(Events { tx, conn_id: 7 }),
+                ..Config::default()
+            };
+            let (mut peer, local) = tokio::io::duplex(1024);
+            let (mut msgs, _tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+            peer.write_all(&raw).await.unwrap();
+            while msgs.recv().await.is_some() {}
+            let mut events = Vec::new();
+            while let Ok(event) = rx.try_recv() {
+                events.push(event);
+            }
+            events
+        };
+
+        let raw = FrameWriter::new(true, None).close(Some((1000, "bye".to_owned())));
+        let evs = events(raw).await;
+        assert_eq!(2, evs.len());
+        assert!(matches!(evs[0], Event::Open { id: 7 }));
+        match &evs[1] {
+            Event::Close { id, code, reason } => assert_eq!((7, 1000, "bye"), (*id, *code, reason.as_str())),
+            other => panic!("expected close got {:?}", other),
         }
-        if self.fin && self.opcode.continuation() {
-            return Fragment::End;
+
+        // unmasked frame from the client
+        let raw = FrameWriter::new(false, None).text("abc".to_owned());
+        let evs = events(raw).await;
+        assert_eq!(3, evs.len());
+        match (&evs[1], &evs[2]) {
+            (Event::Error { error, .. }, Event::Close { code, .. }) => {
+                assert!(matches!(error, Error::WrongHeader(_)));
+                assert_eq!(STATUS_PROTOCOL_ERROR, *code);
+            }
+            other => panic!("expected error and close got {:?}", other),
         }
-        Fragment::None
     }
-    fn is_fragment(&self) -> bool {
-        !(self.fin && !self.opcode.continuation())
+
+    #[tokio::test]
+    async fn stats() {
+        let config = Config::default();
+        let stats = config.stats.clone();
+        let deflate = Some(Deflate::default());
+        let (mut peer, local) = tokio::io::duplex(64 * 1024);
+        let (mut rx, tx) = start(Stream::new(local), true, deflate, config, crate::log::null()).await;
+
+        tx.send(Msg::Text("abc".to_owned())).await.unwrap();
+        let mut text = [0u8; 5];
+        peer.read_exact(&mut text).await.unwrap();
+
+        let mut fw = FrameWriter::new(true, deflate);
+        let compressed = fw.text("a".repeat(1000));
+        let mut raw = compressed.clone();
+        fw.max_frame_size = Some(4);
+        raw.extend(fw.binary(vec![1; 10])); // 3 fragments
+        raw.extend(fw.ping(Vec::new()));
+        raw.extend(fw.close(Some((1000, String::new()))));
+        peer.write_all(&raw).await.unwrap();
+        while rx.recv().await.is_some() {}
+        let mut rest = Vec::new();
+        peer.read_to_end(&mut rest).await.unwrap(); // pong and close echo
+
+        assert_eq!(6, stats.frames_in());
+        assert_eq!(3, stats.fragments_in());
+        assert_eq!(2, stats.messages_in());
+        assert_eq!(raw.len() as u64, stats.bytes_in());
+        assert_eq!((1, 0), (stats.pings(), stats.pongs()));
+        // compressed frame has 6 bytes of header, with the masking key
+        assert_eq!(1000 - (compressed.len() as u64 - 6), stats.compressed_bytes_saved());
+        let ratio = 1000.0 / (compressed.len() - 6) as f64;
+        assert!((stats.compression_ratio_in() - ratio).abs() < 1e-9);
+        assert_eq!(1000, stats.close_code());
+        assert_eq!(3, stats.frames_out());
+        assert_eq!(1, stats.messages_out());
+        assert_eq!(5 + rest.len() as u64, stats.bytes_out());
     }
 
-    // if frame is part of the fragmented message it is appended to the current
-    // fragment returns frame, and fragment
-    // if frame is None it is not completed
-    fn into_fragment(self, fragment: Option<Frame>) -> (Option<Frame>, Option<Frame>) {
-        match self.fragment() {
-            Fragment::Start => (None, Some(self)),
-            Fragment::Middle => {
-                let mut f = fragment.unwrap();
-                f.append(&self);
-                (None, Some(f))
+    #[tokio::test]
+    async fn control_msgs() {
+        let mut raw = FrameWriter::new(false, None).ping(b"abc".to_vec());
+        raw.extend(FrameWriter::new(false, None).pong(b"abc".to_vec()));
+        let config = Config {
+            auto_pong: false,
+            ..Config::default()
+        };
+        let msgs = read_msgs(raw.clone(), false, config).await;
+        assert_eq!(2, msgs.len()); // ping left for the application to answer
+        assert_eq!("ping", msgs[0].kind());
+
+        let msgs = read_msgs(raw.clone(), false, Config::default()).await;
+        assert_eq!(1, msgs.len()); // only close
+
+        let config = Config {
+            control_msgs: true,
+            ..Config::default()
+        };
+        let msgs = read_msgs(raw, false, config).await;
+        assert_eq!(2, msgs.len()); // ping answered by the reader
+        assert_eq!("pong", msgs[0].kind());
+    }
+
+    #[tokio::test]
+    async fn unsolicited_pong() {
+        // pong without any ping sent is a heartbeat, connection stays open
+        let mut fw = FrameWriter::new(true, None);
+        for control_msgs in &[false, true] {
+            let config = Config {
+                control_msgs: *control_msgs,
+                ..Config::default()
+            };
+            let (mut peer, local) = tokio::io::duplex(1024);
+            let (mut rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+            peer.write_all(&fw.pong(b"never pinged".to_vec())).await.unwrap();
+            peer.write_all(&fw.text("abc".to_owned())).await.unwrap();
+            if *control_msgs {
+                match rx.recv().await {
+                    Some(Msg::Pong(payload)) => assert_eq!(b"never pinged".to_vec(), payload),
+                    other => panic!("expected pong got {:?}", other),
+                }
             }
-            Fragment::End => {
-                let mut f = fragment.unwrap();
-                f.append(&self);
-                (Some(f), None)
+            match rx.recv().await {
+                Some(Msg::Text(text)) => assert_eq!("abc", text),
+                other => panic!("expected text got {:?}", other),
             }
-            Fragment::None => (Some(self), fragment),
+            // pong is not answered, close is the first frame sent
+            drop(tx);
+            let mut close = [0u8; 2];
+            peer.read_exact(&mut close).await.unwrap();
+            assert_eq!([0x88, 0x00], close);
         }
     }
 
-    fn status(&self) -> u16 {
-        if self.payload_len != 2 {
-            return 0;
+    #[tokio::test]
+    async fn keepalive() {
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let config = Config {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let (mut rx, _tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        // idle connection gets pings
+        let mut ping = [0u8; 2];
+        peer.read_exact(&mut ping).await.unwrap();
+        assert_eq!([0x89, 0x00], ping);
+        // peer doesn't respond, connection is closed without the close
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&[rx.recv().await.unwrap()]));
+    }
+
+    #[tokio::test]
+    async fn pong_timeout() {
+        let config = Config {
+            pong_timeout: Some(Duration::from_millis(30)),
+            ..Config::default()
+        };
+        let mut fw = FrameWriter::new(true, None);
+
+        // stale pong doesn't stop the timer
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, tx) = start(Stream::new(local), true, None, config.clone(), crate::log::null()).await;
+        tx.send(Msg::Ping(b"1".to_vec())).await.unwrap();
+        let mut ping = [0u8; 3];
+        peer.read_exact(&mut ping).await.unwrap();
+        assert_eq!([0x89, 0x01, b'1'], ping);
+        peer.write_all(&fw.pong(b"0".to_vec())).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Failed(Timeout::Pong)) => (),
+            other => panic!("expected pong timeout got {:?}", other),
         }
-        let bytes: [u8; 2] = [self.payload[0], self.payload[1]];
-        let status = u16::from_be_bytes(bytes);
-        match status {
-            1000 | 1001 | 1002 | 1003 | 1007 | 1008 | 1009 | 1010 | 1011 => status, /* valid status code, reply with */
-            // that code
-            _ => 0, // for all other reply with close frame without payload
+
+        // matching pong
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        tx.send(Msg::Ping(b"1".to_vec())).await.unwrap();
+        peer.read_exact(&mut ping).await.unwrap();
+        peer.write_all(&fw.pong(b"1".to_vec())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        peer.write_all(&fw.text("abc".to_owned())).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Text(text)) => assert_eq!("abc", text),
+            other => panic!("expected text got {:?}", other),
         }
     }
 
-    fn append(&mut self, other: &Frame) -> &Frame {
-        self.payload_len = self.payload_len + other.payload_len;
-        self.payload.extend_from_slice(&other.payload);
-        self
+    #[tokio::test]
+    async fn keepalive_pong_timeout() {
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let config = Config {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            pong_timeout: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+        let (mut rx, _tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        // keepalive pings are numbered
+        let mut ping = [0u8; 6];
+        peer.read_exact(&mut ping).await.unwrap();
+        assert_eq!([0x89, 0x04, 0, 0, 0, 1], ping);
+        match rx.recv().await {
+            Some(Msg::Failed(Timeout::Pong)) => (),
+            other => panic!("expected pong timeout got {:?}", other),
+        }
     }
 
-    fn into_ws_msg(self) -> Msg {
-        match self.opcode.value() {
-            TEXT => Msg::Text(self.text_payload),
-            BINARY => Msg::Binary(self.payload),
-            PING => Msg::Ping(self.payload),
-            PONG => Msg::Pong(self.payload),
-            CLOSE => Msg::Close(self.status()),
-            _ => Msg::Close(0),
+    #[tokio::test]
+    async fn read_timeout() {
+        let config = Config {
+            read_timeout: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        let raw = FrameWriter::new(true, None).text("abc".to_owned());
+        // idle connection is not closed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        peer.write_all(&raw).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Text(text)) => assert_eq!("abc", text),
+            other => panic!("expected text got {:?}", other),
+        }
+        // peer stalls in the middle of the frame
+        peer.write_all(&raw[..4]).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Failed(Timeout::Read)) => (),
+            other => panic!("expected read timeout got {:?}", other),
         }
+        // connection is closed while the application still holds the socket
+        let mut rest = Vec::new();
+        let eof = tokio::time::timeout(Duration::from_millis(100), peer.read_to_end(&mut rest)).await;
+        assert_eq!(0, eof.unwrap().unwrap());
     }
-}
 
-//Converts masked data into unmasked data, or vice versa.
-//The same algorithm applies regardless of the direction of the translation,
-//e.g., the same steps are applied to ask the data as to unmask the data.
-fn mask(payload: &mut Vec<u8>, key: [u8; 4]) {
-    // loop through the octets of ENCODED and XOR the octet with the (i modulo 4)th
-    // octet of MASK ref: https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers
-    for (i, b) in payload.iter_mut().enumerate() {
-        *b = *b ^ key[i % 4];
+    #[tokio::test]
+    async fn write_timeout() {
+        let config = Config {
+            write_timeout: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let stats = config.stats.clone();
+        // peer is not reading
+        let (_peer, local) = tokio::io::duplex(16);
+        let (mut rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        tx.send(Msg::Binary(vec![0u8; 1024])).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Failed(Timeout::Write)) => (),
+            other => panic!("expected write timeout got {:?}", other),
+        }
+        // frame which was not written is not counted
+        assert_eq!((0, 0), (stats.frames_out(), stats.bytes_out()));
     }
-}
 
-struct FrameWriter {
-    mask: bool,
-}
+    #[test]
+    fn validate_rsv() {
+        let validate = |byte1: u8, in_continuation: bool| Frame::new(byte1, 0).validate(false, true, in_continuation);
+        assert!(validate(0b1100_0001, false).is_ok()); // rsv1 on text
+        assert!(validate(0b0100_0010, false).is_ok()); // rsv1 on the first fragment
+        assert!(validate(0b1000_0000, true).is_ok()); // continuation
+        assert!(validate(0b1100_0000, true).is_err()); // rsv1 on continuation
+        assert!(validate(0b1100_1001, false).is_err()); // rsv1 on ping
+        assert!(validate(0b1010_0001, false).is_err()); // rsv2
+        assert!(validate(0b1001_0001, false).is_err()); // rsv3
 
-impl FrameWriter {
-    fn new(mask: bool) -> Self {
-        Self { mask: mask }
+        // rsv1 without deflate
+        assert!(Frame::new(0b1100_0001, 0).validate(false, false, false).is_err());
     }
 
-    fn ping(&self, payload: Vec<u8>) -> Vec<u8> {
-        self.build(PING, payload)
+    #[tokio::test]
+    async fn continuation_with_rsv1() {
+        // fragmented message with rsv1 set on the continuation frame
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let deflate = Some(Deflate::default());
+        let (mut rx, _tx) = start(
+            Stream::new(local),
+            false,
+            deflate,
+            Config::default(),
+            crate::log::null(),
+        )
+        .await;
+        peer.write_all(&[0x01, 0x01, b'a', 0xc0, 0x01, b'b']).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Close(Some((status, _)))) => assert_eq!(STATUS_PROTOCOL_ERROR, status),
+            other => panic!("expected close got {:?}", other),
+        }
     }
 
-    fn pong(&self, payload: Vec<u8>) -> Vec<u8> {
-        self.build(PONG, payload)
+    #[tokio::test]
+    async fn continuation_as_first_frame() {
+        // validation fails before the frame reaches the fragment assembly
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        // masked continuation frame with fin set
+        let frame = [0x80, 0x83, 0, 0, 0, 0, b'a', b'b', b'c'];
+        peer.write_all(&frame).await.unwrap();
+        match rx.recv().await {
+            Some(Msg::Close(Some((status, _)))) => assert_eq!(STATUS_PROTOCOL_ERROR, status),
+            other => panic!("expected close got {:?}", other),
+        }
+        assert!(rx.recv().await.is_none());
+        // close with the status is sent to the peer
+        let mut close = [0u8; 4];
+        peer.read_exact(&mut close).await.unwrap();
+        assert_eq!([0x88, 0x02, 0x03, 0xea], close);
     }
 
-    fn close(&self, status: u16) -> Vec<u8> {
-        if status == 0 {
-            self.build(CLOSE, Vec::new())
-        } else {
-            self.build(CLOSE, status.to_be_bytes().to_vec())
-        }
-    }
-
-    fn binary(&self, payload: Vec<u8>) -> Vec<u8> {
-        self.build(BINARY, payload)
-    }
-
-    fn text(&self, payload: String) -> Vec<u8> {
-        self.build(TEXT, payload.into_bytes())
-    }
-
-    /*
-     0                   1                   2                   3
-     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
-    +-+-+-+-+-------+-+-------------+-------------------------------+
-    |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
-    |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
-    |N|V|V|V|       |S|             |   (if payload len==126/127)   |
-    | |1|2|3|       |K|             |                               |
-    +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
-    |     Extended payload length continued, if payload len == 127  |
-    + - - - - - - - - - - - - - - - +-------------------------------+
-    |                               |Masking-key, if MASK set to 1  |
-    +-------------------------------+-------------------------------+
-    | Masking-key (continued)       |          Payload Data         |
-    +-------------------------------- - - - - - - - - - - - - - - - +
-    :                     Payload Data continued ...                :
-    + - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - +
-    |                     Payload Data continued ...                |
-    +---------------------------------------------------------------+
-    */
-    fn build(&self, opcode: u8, mut payload: Vec<u8>) -> Vec<u8> {
-        let mut buf = vec![0b1000_0000u8 + opcode];
-
-        // add payload length
-        let l = payload.len();
-        if l < 126 {
-            buf.push(l as u8);
-        } else if payload.len() < 65536 {
-            buf.push(126u8);
-            let l = l as u16;
-            buf.extend_from_slice(&l.to_be_bytes());
-        } else {
-            buf.push(127u8);
-            let l = l as u64;
-            buf.extend_from_slice(&l.to_be_bytes());
+    #[tokio::test]
+    async fn close_when_receiver_dropped() {
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        drop(rx);
+        let mut close = [0u8; 2];
+        peer.read_exact(&mut close).await.unwrap();
+        assert_eq!([0x88, 0x00], close);
+    }
+
+    #[test]
+    fn opcode_conversion() {
+        for n in 0..16u8 {
+            assert_eq!(n, Opcode::from_u8(n).to_u8());
         }
-        if self.mask {
-            buf[1] = buf[1] | 0b1000_0000u8; // set masking bit
-            let masking_key = rand::thread_rng().gen::<[u8; 4]>(); // create key
-            buf.extend_from_slice(&masking_key); // write key to msg
-            mask(&mut payload, masking_key) // mask payload
+        assert_eq!(Opcode::Pong, Opcode::from_u8(10));
+        assert_eq!(Opcode::Reserved(3), Opcode::from_u8(3));
+        assert!(Opcode::Close.control() && !Opcode::Close.data());
+        assert!(!Opcode::Continuation.control() && !Opcode::Continuation.data());
+    }
+
+    #[tokio::test]
+    async fn reserved_opcode() {
+        let raw = vec![0x83, 0x00];
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn ping_flood() {
+        let config = Config {
+            max_control_rate: Some(100),
+            ..Config::default()
+        };
+        let mut raw = Vec::new();
+        for _ in 0..1000 {
+            raw.extend(FrameWriter::new(false, None).ping(Vec::new()));
         }
-        buf.extend_from_slice(payload.as_slice());
-        buf
+        let msgs = read_msgs(raw.clone(), false, config).await;
+        assert_eq!(STATUS_POLICY_VIOLATION, close_status(&msgs));
+
+        // no limit by default
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&msgs));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use miniz_oxide::deflate::compress_to_vec;
-    use miniz_oxide::inflate::decompress_to_vec;
+    #[tokio::test]
+    async fn illegal_fragmentation() {
+        use Opcode::{Binary, Continuation, Text};
+        let fw = FrameWriter::new(false, None);
+        let cases: Vec<(&str, Vec<(bool, Opcode)>)> = vec![
+            ("start then start", vec![(false, Text), (false, Text)]),
+            ("start then text", vec![(false, Text), (true, Text)]),
+            ("start then binary", vec![(false, Text), (true, Binary)]),
+            ("binary start then text start", vec![(false, Binary), (false, Text)]),
+            ("middle without start", vec![(false, Continuation)]),
+            ("end without start", vec![(true, Continuation)]),
+            ("end after completed message", vec![(true, Text), (true, Continuation)]),
+            (
+                "start after middle",
+                vec![(false, Binary), (false, Continuation), (false, Binary)],
+            ),
+        ];
+        for (name, frames) in cases {
+            let mut raw = Vec::new();
+            for (fin, opcode) in frames {
+                raw.extend(fw.frame(fin, opcode, false, b"a"));
+            }
+            // followed by the valid message which must not be read
+            raw.extend(fw.frame(true, Text, false, b"b"));
+            let msgs = read_msgs(raw, false, Config::default()).await;
+            let kinds: Vec<&str> = msgs.iter().map(|m| m.kind()).collect();
+            // only completed message before the violation is delivered
+            let expected = if name == "end after completed message" {
+                vec!["text", "close"]
+            } else {
+                vec!["close"]
+            };
+            assert_eq!(expected, kinds, "{}", name);
+            assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs), "{}", name);
+        }
+    }
 
-    fn text_frame(text: &str) -> Vec<u8> {
-        FrameWriter::new(false).text(text.to_owned())
+    #[tokio::test]
+    async fn payload_length_msb() {
+        let raw = vec![0x82, 0x7f, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn non_minimal_payload_length() {
+        // length 10 in the 16-bit form
+        let mut raw = vec![0x82, 0x7e, 0, 10];
+        raw.extend_from_slice(&[0; 10]);
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+
+        // length 200 in the 64-bit form
+        let mut raw = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 0, 0, 200];
+        raw.extend_from_slice(&[0; 200]);
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+
+        // minimal forms are accepted
+        let mut raw = vec![0x82, 0x7e, 0, 126];
+        raw.extend_from_slice(&[0; 126]);
+        let mut long = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0];
+        long.extend_from_slice(&[0; 65536]);
+        raw.extend(long);
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        match &msgs[..] {
+            [Msg::Binary(short), Msg::Binary(long), ..] => {
+                assert_eq!(126, short.len());
+                assert_eq!(65536, long.len());
+            }
+            other => panic!("expected two binary got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_handshake() {
+        let config = Config {
+            close_timeout: Duration::from_millis(20),
+            ..Config::default()
+        };
+        let expected_close = [0x88, 0x05, 0x03, 0xe8, b'b', b'y', b'e'];
+
+        // peer doesn't reply, stream is closed after timeout
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, tx) = start(Stream::new(local), true, None, config.clone(), crate::log::null()).await;
+        tx.send(Msg::close(1000, "bye".to_owned())).await.unwrap();
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(expected_close, buf[..]);
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&[rx.recv().await.unwrap()]));
+        assert!(rx.recv().await.is_none());
+
+        // peer replies with close
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let config = Config {
+            close_timeout: Duration::from_secs(60),
+            ..config
+        };
+        let (mut rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        tx.send(Msg::close(1000, "bye".to_owned())).await.unwrap();
+        let mut close = [0u8; 7];
+        peer.read_exact(&mut close).await.unwrap();
+        assert_eq!(expected_close, close);
+        peer.write_all(&FrameWriter::new(true, None).close(Some((1000, String::new()))))
+            .await
+            .unwrap();
+        assert_eq!(1000, close_status(&[rx.recv().await.unwrap()]));
+        assert_eq!(0, peer.read(&mut close).await.unwrap()); // eof
+    }
+
+    #[tokio::test]
+    async fn abort() {
+        let config = Config::default();
+        let stats = config.stats.clone();
+        let abort = config.abort.clone();
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (mut rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        // writer is stuck, peer is not reading
+        tx.send(Msg::Binary(vec![0; 64 * 1024])).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        abort.abort();
+        assert!(rx.recv().await.is_none());
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        // only the part of the message, no close frame
+        assert_eq!(1024, buf.len());
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, stats.close_code());
+        assert!(tx.send(Msg::Text("abc".to_owned())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn overflow() {
+        for (overflow, kept) in &[(Overflow::DropOldest, ["2", "3"]), (Overflow::DropNewest, ["1", "2"])] {
+            let config = Config {
+                buffer_size: 2,
+                close_timeout: Duration::from_millis(20),
+                outbox: Outbox::new(*overflow, 2),
+                ..Config::default()
+            };
+            let outbox = config.outbox.clone();
+            let (mut peer, local) = tokio::io::duplex(1024);
+            let (_rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+            // writer is stuck, peer is not reading
+            let big = Msg::Binary(vec![0; 4096]);
+            let mut expected = big.into_raw(&mut FrameWriter::new(false, None));
+            outbox.send(&tx, Msg::Binary(vec![0; 4096])).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            assert!(outbox.send(&tx, Msg::Text("1".to_owned())).await.unwrap());
+            assert!(outbox.send(&tx, Msg::Text("2".to_owned())).await.unwrap());
+            assert!(!outbox.send(&tx, Msg::Text("3".to_owned())).await.unwrap());
+            // close goes through the channel, but after the queued messages
+            assert!(outbox.send(&tx, Msg::close(1000, String::new())).await.unwrap());
+            for text in kept {
+                expected.extend(text_frame(text));
+            }
+            expected.extend(FrameWriter::new(false, None).close(Some((1000, String::new()))));
+
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(expected, buf);
+            assert!(outbox.send(&tx, Msg::Text("4".to_owned())).await.is_err());
+        }
     }
 
     #[test]
-    fn test_text_message() {
-        let buf = text_frame("abc");
-        assert_eq!(5, buf.len());
-        assert_eq!([0x81, 0x03, 0x61, 0x62, 0x63], buf[0..]);
+    fn overflow_keeps_appended() {
+        let outbox = Outbox::new(Overflow::DropOldest, 2);
+        assert!(outbox.push(Msg::Text("1".to_owned())));
+        outbox.append(Msg::close(1000, String::new()));
+        outbox.append(Msg::Fragment {
+            first: true,
+            fin: false,
+            payload: vec![1],
+        });
+        assert!(outbox.push(Msg::Text("2".to_owned())));
+        assert!(!outbox.push(Msg::Text("3".to_owned())));
+        assert!(!outbox.push(Msg::Text("4".to_owned())));
 
-        let buf = text_frame(
-            "The length of the Payload data, in bytes: if 0-125, that is the payload length.  
-        If 126, the following 2 bytes interpreted as a 16-bit unsigned integer are the payload length.",
-        );
-        assert_eq!(188, buf.len());
-        assert_eq!([0x81, 0x7e, 0x00, 184], buf[0..4]);
-        assert_eq!(184, buf[3]); // 175 is body length
+        let mut kinds = Vec::new();
+        while let Some(msg) = outbox.try_pop() {
+            kinds.push(match msg {
+                Msg::Text(text) => text,
+                msg => msg.kind().to_owned(),
+            });
+        }
+        assert_eq!(vec!["close", "fragment", "3", "4"], kinds);
+    }
 
-        //println!("{:02x?}", buf);
+    #[tokio::test]
+    async fn abnormal_closure() {
+        let (peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+        drop(peer);
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&[rx.recv().await.unwrap()]));
+
+        // echo of the abnormal closure goes to the peer without status
+        let raw = Msg::close(STATUS_ABNORMAL_CLOSURE, String::new()).into_raw(&mut FrameWriter::new(false, None));
+        assert_eq!([0x88, 0x00], raw[..]);
+    }
+
+    #[tokio::test]
+    async fn half_close() {
+        // peer shuts down its write side without the close frame
+        let config = Config {
+            close_timeout: Duration::from_secs(10),
+            ..Config::default()
+        };
+        let stats = config.stats.clone();
+        let (peer, local) = tokio::io::duplex(1024);
+        let (mut rx, _tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        let (mut peer_rh, mut peer_wh) = tokio::io::split(peer);
+        peer_wh.shutdown().await.unwrap();
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&[rx.recv().await.unwrap()]));
+        assert!(rx.recv().await.is_none());
+
+        // close without status is still written, stream is closed without
+        // waiting for the close timeout
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(1), peer_rh.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!([0x88, 0x00], buf[..]);
+        assert_eq!(STATUS_ABNORMAL_CLOSURE, stats.close_code());
+
+        // in the middle of the frame header and payload
+        for raw in &[vec![0x81, 0xfe, 0x01], vec![0x81, 0x83, 0, 0, 0, 0, b'a']] {
+            let (peer, local) = tokio::io::duplex(1024);
+            let (mut rx, _tx) = start(Stream::new(local), true, None, Config::default(), crate::log::null()).await;
+            let (mut peer_rh, mut peer_wh) = tokio::io::split(peer);
+            peer_wh.write_all(raw).await.unwrap();
+            peer_wh.shutdown().await.unwrap();
+            assert_eq!(STATUS_ABNORMAL_CLOSURE, close_status(&[rx.recv().await.unwrap()]));
+            let mut buf = Vec::new();
+            peer_rh.read_to_end(&mut buf).await.unwrap();
+            assert_eq!([0x88, 0x00], buf[..]);
+        }
     }
+
+    // Counts writes to the underlying stream.
+    struct CountingWriter<W> {
+        inner: W,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_writes() {
+        for (coalesce_writes, expected_writes) in &[(false, 11), (true, 1)] {
+            let config = Config {
+                coalesce_writes: *coalesce_writes,
+                close_timeout: Duration::from_millis(20),
+                ..Config::default()
+            };
+            let (mut peer, local) = tokio::io::duplex(64 * 1024);
+            let (rh, wh) = tokio::io::split(local);
+            let writes = Arc::new(AtomicUsize::new(0));
+            let wh = stream::WriteHalf::new(CountingWriter {
+                inner: wh,
+                writes: writes.clone(),
+            });
+            let stream = Stream::new_from_halfs(stream::ReadHalf::new(rh), wh);
+            let (_rx, tx) = start(stream, true, None, config, crate::log::null()).await;
+            // all messages are queued before the Writer runs
+            let mut expected = Vec::new();
+            for i in 0..10 {
+                tx.send(Msg::Text(i.to_string())).await.unwrap();
+                expected.extend(text_frame(&i.to_string()));
+            }
+            tx.send(Msg::close(1000, String::new())).await.unwrap();
+            expected.extend(FrameWriter::new(false, None).close(Some((1000, String::new()))));
+
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(expected, buf);
+            assert_eq!(*expected_writes, writes.load(Ordering::Relaxed));
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_close_status() {
+        let config = Config {
+            drop_close_status: 1001,
+            close_timeout: Duration::from_millis(20),
+            ..Config::default()
+        };
+        let (mut peer, local) = tokio::io::duplex(1024);
+        let (_rx, tx) = start(Stream::new(local), true, None, config, crate::log::null()).await;
+        drop(tx);
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!([0x88, 0x02, 0x03, 0xe9], buf[..]);
+    }
+
+    #[tokio::test]
+    async fn client_rejects_masked_frame() {
+        let raw = FrameWriter::new(true, None).text("abc".to_owned());
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+    }
+
     #[test]
-    fn test_compress_decompress() {
-        let data = "hello world";
-        let compressed = compress_to_vec(data.as_bytes(), 6);
-        let decompressed = decompress_to_vec(compressed.as_slice()).expect("Failed to decompress!");
-        assert_eq!(data, str::from_utf8(&decompressed).unwrap());
+    fn frame_writer_deflate() {
+        let text = "The length of the Payload data, in bytes: if 0-125, that is the payload length. ".repeat(10);
+        let buf = FrameWriter::new(false, Some(Deflate::default())).text(text.clone());
+        assert_eq!(0b1100_0001, buf[0]); // fin, rsv1, text
+        assert!(buf.len() < text.len());
+        let f = parse_frame(&buf);
+        assert!(f.validate(false, true, false).is_ok());
+        let payload = Inflater::new(Deflate::default())
+            .inflate(&f.payload, text.len())
+            .unwrap();
+        assert_eq!(text.as_bytes(), &payload[..]);
+
+        // short messages are not compressed
+        let buf = FrameWriter::new(false, Some(Deflate::default())).text("abc".to_owned());
+        assert_eq!([0x81, 0x03, 0x61, 0x62, 0x63], buf[0..]);
+
+        // control frames are never compressed
+        let buf = FrameWriter::new(false, Some(Deflate::default())).ping(vec![0u8; 125]);
+        assert_eq!(0b1000_1001, buf[0]);
+        assert_eq!(127, buf.len());
+    }
+
+    #[tokio::test]
+    async fn frame_writer_fragments() {
+        let mut fw = FrameWriter::new(false, None);
+        fw.max_frame_size = Some(10);
+        let text = "a".repeat(25);
+        let raw = fw.text(text.clone());
+        assert_eq!(3 * 2 + 25, raw.len());
+        assert_eq!([0x01, 10], raw[0..2]); // text, no fin
+        assert_eq!([0x00, 10], raw[12..14]); // continuation
+        assert_eq!([0x80, 5], raw[24..26]); // continuation, fin
+        let msgs = read_msgs(raw, false, Config::default()).await;
+        assert_eq!(2, msgs.len());
+        match &msgs[0] {
+            Msg::Text(t) => assert_eq!(&text, t),
+            other => panic!("expected text got {:?}", other),
+        }
+
+        // control frames are not fragmented
+        let raw = fw.ping(vec![0u8; 25]);
+        assert_eq!([0x89, 25], raw[0..2]);
+        assert_eq!(27, raw.len());
+    }
+
+    #[tokio::test]
+    async fn fragmented_compressed_message() {
+        let mut fw = FrameWriter::new(false, Some(Deflate::default()));
+        fw.max_frame_size = Some(16);
+        let text: String = (0..200).map(|i| (b'a' + (i * 7 % 26) as u8) as char).collect();
+        let raw = fw.text(text.clone());
+        // rsv1 only in the first frame
+        let first = parse_frame(&raw);
+        assert!(first.rsv1 && !first.fin && first.opcode == Opcode::Text);
+        let second = parse_frame(&raw[18..]);
+        assert!(!second.rsv1 && second.opcode == Opcode::Continuation);
+
+        let msgs = read_deflate_msgs(raw.clone(), false, Some(Deflate::default()), Config::default()).await;
+        assert_eq!(2, msgs.len());
+        match &msgs[0] {
+            Msg::Text(t) => assert_eq!(&text, t),
+            other => panic!("expected text got {:?}", other),
+        }
+
+        // continuation frame with rsv1 set
+        let mut raw = raw;
+        raw[18] |= 0b0100_0000;
+        let msgs = read_deflate_msgs(raw, false, Some(Deflate::default()), Config::default()).await;
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
     }
+
+    #[tokio::test]
+    async fn compressed_fragments_max_message_size() {
+        // compressed to less than 1000 bytes
+        let payload = deflate(&vec![0u8; 100_000]);
+        let half = payload.len() / 2;
+        let fw = FrameWriter::new(false, None);
+        let mut raw = fw.frame(false, Opcode::Binary, true, &payload[..half]);
+        raw.extend(fw.frame(true, Opcode::Continuation, false, &payload[half..]));
+        let read = |max_message_size: usize| {
+            let config = Config {
+                max_message_size,
+                ..Config::default()
+            };
+            read_deflate_msgs(raw.clone(), false, Some(Deflate::default()), config)
+        };
+
+        let msgs = read(100_000).await;
+        assert_eq!(2, msgs.len());
+        match &msgs[0] {
+            Msg::Binary(data) => assert_eq!(100_000, data.len()),
+            other => panic!("expected binary got {:?}", other),
+        }
+        // fragments fit, inflated message doesn't
+        let msgs = read(99_999).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&msgs));
+        // second fragment exceeds the limit before inflating
+        let msgs = read(half + 1).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_MESSAGE_TOO_BIG, close_status(&msgs));
+    }
+
+    #[tokio::test]
+    async fn corrupt_compressed_message() {
+        let raw = FrameWriter::new(false, None).frame(true, Opcode::Binary, true, &[0xff, 0xff]);
+        let (tx, mut rx) = mpsc::channel(8);
+        let config = Config {
+            events: Some(Events { tx, conn_id: 1 }),
+            ..Config::default()
+        };
+        let msgs = read_deflate_msgs(raw, false, Some(Deflate::default()), config).await;
+        assert_eq!(1, msgs.len());
+        assert_eq!(STATUS_PROTOCOL_ERROR, close_status(&msgs));
+        match rx.recv().await {
+            Some(Event::Error { error, .. }) => assert!(matches!(error, Error::InflateFailed(_))),
+            other => panic!("expected inflate error got {:?}", other),
+        }
+    }
+
     #[test]
-    fn frame_inflate() {
-        let mut f = Frame::new(0, 0);
-        f.rsv1 = true;
-        f.rsv = 4;
-        f.opcode = Opcode::new(1);
-        f.payload_len = 7;
-        f.payload = vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
-        assert_eq!(true, f.validate_payload().is_ok());
-        assert_eq!("Hello", f.text_payload);
+    fn inflate_empty_message() {
+        // no block, single empty stored block header, empty static block
+        for empty in [&[][..], &[0x00], &[0x02, 0x00]] {
+            let mut deflater = Deflater::new(Deflate::default());
+            let mut inflater = Inflater::new(Deflate::default());
+            let hello = deflater.deflate(b"hello hello").unwrap();
+            assert_eq!(b"hello hello", &inflater.inflate(&hello, 100).unwrap()[..]);
+            assert!(inflater.inflate(empty, 100).unwrap().is_empty());
+            // context is not broken by the empty message
+            let hello = deflater.deflate(b"hello hello").unwrap();
+            assert_eq!(b"hello hello", &inflater.inflate(&hello, 100).unwrap()[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_compressed_message() {
+        let mut raw = vec![0xc1, 0x00, 0xc2, 0x01, 0x00];
+        raw.extend(FrameWriter::new(false, Some(Deflate::default())).text("a".repeat(100)));
+        let msgs = read_deflate_msgs(raw, false, Some(Deflate::default()), Config::default()).await;
+        match &msgs[..] {
+            [Msg::Text(empty), Msg::Binary(binary), Msg::Text(text), Msg::Close(..)] => {
+                assert!(empty.is_empty() && binary.is_empty());
+                assert_eq!("a".repeat(100), *text);
+            }
+            other => panic!("expected empty text, binary and text got {:?}", other),
+        }
     }
 }